@@ -16,10 +16,18 @@ use std::path::PathBuf;
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
+    //
+    // With the `flash` feature, the app runs from flash instead of RAM, for
+    // when it outgrows RAM, or to try it the way it ships.
+    let memory_x: &[u8] = if env::var_os("CARGO_FEATURE_FLASH").is_some() {
+        include_bytes!("memory-flash.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
@@ -28,6 +36,7 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-flash.x");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
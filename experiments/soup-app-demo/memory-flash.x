@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* Slot A, right above stage0 */
+  FLASH : ORIGIN = 0x00008000, LENGTH = 492K
+  /* All of it, but stage0's boot magic in the last 64 bytes */
+  RAM : ORIGIN = 0x20000000, LENGTH = 256K - 64
+}
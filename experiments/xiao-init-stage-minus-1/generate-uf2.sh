@@ -2,8 +2,12 @@
 
 set -euxo pipefail
 
-cargo objcopy --release -- -O binary ./target/minus-1.bin
-uf2conv target/minus-1.bin --base 0x27000 -f 0xADA52840 --output ./target/minus-1.uf2
+cargo build --release
+
+# The ELF says where it goes. soup-cli is built for the host, which the
+# `.cargo/config.toml` here would get in the way of.
+ELF=$PWD/target/thumbv7em-none-eabi/release/xiao-init-stage-minus-1
+(cd ../../host/soup-cli && cargo run --release -- uf2 "$ELF" --family nrf52840 --output "$OLDPWD/target/minus-1.uf2")
 
 set +x
 
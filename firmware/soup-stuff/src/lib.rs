@@ -5,8 +5,8 @@ use core::mem;
 
 use embassy_executor::Spawner;
 use embassy_nrf::{
-    bind_interrupts, pac,
-    peripherals::{self, USBD},
+    bind_interrupts, nvmc::{self, Nvmc}, pac,
+    peripherals::{self, NVMC, USBD},
     usb::{self, vbus_detect::HardwareVbusDetect, Driver},
 };
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, pipe::Pipe};
@@ -18,7 +18,8 @@ use embassy_usb::{
 
 use panic_reset as _;
 use postcard::accumulator::{CobsAccumulator, FeedResult};
-use soup_icd::{Control, FromSoup, Managed, ToSoup};
+use soup_icd::{Control, FromSoup, Hello, Managed, ToSoup};
+use soup_slots::Slots;
 
 pub mod embassy {
     pub use embassy_executor;
@@ -137,6 +138,23 @@ pub mod stdio {
     }
 }
 
+/// Tell stage0 that this image works.
+///
+/// An image booted on trial from a flash slot has to call this before the
+/// next reset, or stage0 goes back to the previous image. Call it once the
+/// app is sure it's healthy, e.g. after the host connected. Does nothing
+/// for an image that isn't on trial, or that runs from RAM.
+pub fn confirm_boot() -> Result<(), nvmc::Error> {
+    let layout = soup_slots::NRF52840;
+    let vtor = unsafe { (*cortex_m::peripheral::SCB::PTR).vtor.read() };
+    let Some(running) = layout.slot_of(vtor) else {
+        return Ok(());
+    };
+
+    let nvmc = Nvmc::new(unsafe { NVMC::steal() });
+    Slots::load(nvmc, layout)?.confirm(running)
+}
+
 #[embassy_executor::task]
 async fn stdout(tx: &'static Mutex<ThreadModeRawMutex, UsbSender>) {
     let mut scratch_in = [0u8; 32];
@@ -302,6 +320,7 @@ async fn minimal(
 
 async fn req_handler<'a>(req: ToSoup<'_>, outbuf: &'a mut [u8]) -> &'a [u8] {
     let resp: Option<FromSoup<'_>> = match req {
+        ToSoup::Hello(_) => Some(FromSoup::Hello(Hello::CURRENT)),
         ToSoup::Control(Control::Reboot) => {
             cortex_m::peripheral::SCB::sys_reset();
         }
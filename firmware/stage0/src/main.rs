@@ -4,7 +4,7 @@
 
 use core::{
     cell::UnsafeCell,
-    mem::{self, MaybeUninit}, sync::atomic::{compiler_fence, Ordering},
+    mem::{self, MaybeUninit}, sync::atomic::{AtomicBool, Ordering},
 };
 
 use cortex_m::{singleton, interrupt, peripheral::SCB};
@@ -16,7 +16,7 @@ use embassy_time::{Timer, Duration};
 #[cfg(feature = "small")]
 use panic_reset as _;
 use embassy_executor::Spawner;
-use embassy_futures::join::join;
+use embassy_futures::join::join3;
 use embassy_nrf::{
     bind_interrupts, pac, peripherals::{self, NVMC}, usb,
     usb::{
@@ -29,13 +29,61 @@ use embassy_usb::{
     driver::EndpointError,
     Builder, Config,
 };
-use postcard::accumulator::{CobsAccumulator, FeedResult};
-use stage0_icd::{Request, Response, Error as IcdError, Managed, PeekBytes, Poked, UnalignedFlashAddr};
-use embedded_storage::nor_flash::{ErrorType, NorFlash};
+use stage0_core::{frame::Frames, magic, After, Board, Flash, Memory, Stage0};
+use stage0_icd::{MemRange, Version};
+use soup_env::parse_decimal;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
 
 const SCRATCH_SIZE: usize = 224 * 1024;
 const MAGIC_SIZE: usize = 64;
 const ACC_SIZE: usize = 512;
+const FLASH_SIZE: usize = 1024 * 1024;
+
+/// Images have to be signed with the secret key belonging to this one. Point
+/// `STAGE0_PUBLIC_KEY` at the `.pub` file from `soup-cli keygen` when building.
+#[cfg(feature = "signed-images")]
+const PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("STAGE0_PUBLIC_KEY"));
+
+/// The A/B slots for flash applications. Slot A starts right after stage0.
+const SLOTS: soup_slots::Layout = soup_slots::NRF52840;
+
+/// How long stage0 waits for a host before booting the flash application.
+/// Set `STAGE0_AUTOBOOT_MS` when building to change it, zero disables autoboot.
+const AUTOBOOT_MS: u64 = match option_env!("STAGE0_AUTOBOOT_MS") {
+    Some(ms) => parse_decimal(ms),
+    None => 2000,
+};
+
+/// A second reset within this long after the first one keeps us in stage0.
+/// The XIAO's only button is reset, so this is how you get back in.
+const DOUBLE_TAP_MS: u64 = 500;
+
+/// Set once a host sends us a whole frame, which cancels the autoboot. The USB
+/// connection itself doesn't count, any PC or powered hub configures it
+/// without a program ever opening the port.
+static HOST_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+const BOOTLOADER_VERSION: Version = Version::from_pkg(
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    env!("CARGO_PKG_VERSION_MINOR"),
+    env!("CARGO_PKG_VERSION_PATCH"),
+);
+
+const CONFIG: stage0_core::Config = stage0_core::Config {
+    version: BOOTLOADER_VERSION,
+    ram: MemRange { start: 0x2000_0000, len: 256 * 1024 },
+    bootloader_size: 32 * 1024,
+    // 16 exceptions + 48 interrupts, rounded up to a power of two words
+    vtor_align: 256,
+    slots: SLOTS,
+    #[cfg(feature = "signed-images")]
+    public_key: Some(*PUBLIC_KEY),
+    #[cfg(not(feature = "signed-images"))]
+    public_key: None,
+};
+
+/// stage0 as it runs on this chip
+type NrfStage0 = Stage0<&'static Ram<SCRATCH_SIZE>, &'static Ram<MAGIC_SIZE>, NrfFlash, NrfBoard>;
 
 #[cfg(feature = "use-defmt")]
 macro_rules! s0log {
@@ -80,72 +128,96 @@ impl<const N: usize> Ram<N> {
         p
     }
 
-    pub fn contains(&'static self, addr: usize, len: usize) -> Result<*mut u8, IcdError> {
-        let (start, end) = self.start_end();
-        let contains = match addr.checked_add(len) {
-            Some(range_end) => (addr >= start) && (range_end <= end),
-            None => false,
-        };
-        if contains {
-            let offset = addr - start;
-            Ok(unsafe { self.as_ptr().add(offset) })
-        } else {
-            Err(IcdError::AddressOutOfRange {
-                request: addr,
-                len,
-                min: start,
-                max: end,
-            })
-        }
-    }
-
     pub fn start_end(&'static self) -> (usize, usize) {
         let start = self.as_ptr() as usize;
         let end = start.checked_add(N).unwrap();
         (start, end)
     }
+}
 
-    pub fn read_to<'a>(&'static self, start: usize, buf: &'a mut [u8]) -> Result<&'a mut [u8], IcdError> {
-        let ptr = self.contains(start, buf.len())?;
+impl<const N: usize> Memory for &'static Ram<N> {
+    fn start(&self) -> usize {
+        self.start_end().0
+    }
 
-        unsafe {
-            let len = buf.len();
-            compiler_fence(Ordering::SeqCst);
+    fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr().cast_const(), N) }
+    }
 
-            // TODO: Do we need volatile here?
-            core::ptr::copy_nonoverlapping(
-                ptr.cast_const(),
-                buf.as_mut_ptr(),
-                len,
-            );
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), N) }
+    }
+}
 
-            compiler_fence(Ordering::SeqCst);
-        }
+/// The NVMC, which can also read flash straight off the bus
+struct NrfFlash(Nvmc<'static>);
 
-        Ok(buf)
+impl NrfFlash {
+    fn new() -> Self {
+        Self(Nvmc::new(unsafe { NVMC::steal() }))
     }
+}
 
-    pub fn write_from(&'static self, start: usize, buf: &[u8]) -> Result<(), IcdError> {
-        let ptr = self.contains(start, buf.len())?;
+impl ErrorType for NrfFlash {
+    type Error = <Nvmc<'static> as ErrorType>::Error;
+}
 
-        unsafe {
-            let len = buf.len();
-            compiler_fence(Ordering::SeqCst);
+impl ReadNorFlash for NrfFlash {
+    const READ_SIZE: usize = Nvmc::READ_SIZE;
 
-            // TODO: Do we need volatile here?
-            core::ptr::copy_nonoverlapping(
-                buf.as_ptr(),
-                ptr,
-                len,
-            );
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
 
-            compiler_fence(Ordering::SeqCst);
-        }
+    fn capacity(&self) -> usize {
+        FLASH_SIZE
+    }
+}
+
+impl NorFlash for NrfFlash {
+    const WRITE_SIZE: usize = Nvmc::WRITE_SIZE;
+    const ERASE_SIZE: usize = Nvmc::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
 
-        Ok(())
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes)
     }
 }
 
+impl Flash for NrfFlash {
+    fn slice(&self, addr: usize, len: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+    }
+}
+
+struct NrfBoard;
+
+impl Board for NrfBoard {
+    fn device_id(&self) -> u64 {
+        let ficr: pac::FICR = unsafe { mem::transmute(()) };
+        let id_lo = ficr.deviceid[0].read().bits() as u64;
+        let id_hi = ficr.deviceid[1].read().bits() as u64;
+        (id_hi << 32) | id_lo
+    }
+
+    /// Nothing else runs until it returns, USB included
+    unsafe fn call(&mut self, addr: u32, args: [u32; 4]) -> u32 {
+        let func: extern "aapcs" fn(u32, u32, u32, u32) -> u32 = mem::transmute(addr as usize);
+
+        // Make sure the pokes that put it there are done before we run it
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+        func(args[0], args[1], args[2], args[3])
+    }
+}
+
+fn stage0() -> NrfStage0 {
+    Stage0::new(&SCRATCH, &MAGIC, NrfFlash::new(), NrfBoard, CONFIG)
+}
+
 bind_interrupts!(struct Irqs {
     USBD => usb::InterruptHandler<peripherals::USBD>;
     POWER_CLOCK => usb::vbus_detect::InterruptHandler;
@@ -163,25 +235,25 @@ async fn blinky(mut led: Output<'static, AnyPin>) {
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    let (magic_1, magic_2) = unsafe {
-        let m = MAGIC.as_ptr().cast::<u32>();
-        let ret = (m.read_unaligned(), m.add(1).read_unaligned());
-        m.write_unaligned(0);
-        m.add(1).write_unaligned(0);
-        ret
-    };
+    let mut magic_ram = &MAGIC;
 
-    match (magic_1, magic_2) {
-        (0x0FACADE0, addr) => unsafe {
+    if let Some(addr) = magic::take_boot(&mut magic_ram) {
+        unsafe {
             // bootload!
             let scb: SCB = mem::transmute(());
             scb.vtor.write(addr);
             cortex_m::asm::bootload(addr as *const u32);
         }
+    }
+
+    // Did someone ask us to stay, either over USB or by resetting twice?
+    let stay = match (magic::take(&mut magic_ram, magic::STAY_IDX), magic::take(&mut magic_ram, magic::DOUBLE_TAP_IDX)) {
+        (magic::STAY, _) | (_, magic::DOUBLE_TAP) => true,
         _ => {
-            // Do nothing!
+            magic::set(&mut magic_ram, magic::DOUBLE_TAP_IDX, magic::DOUBLE_TAP);
+            false
         }
-    }
+    };
 
     let p = embassy_nrf::init(Default::default());
     let clock: pac::CLOCK = unsafe { mem::transmute(()) };
@@ -259,7 +331,31 @@ async fn main(spawner: Spawner) {
 
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, echo_fut).await;
+    join3(usb_fut, echo_fut, autoboot(stay)).await;
+}
+
+/// Boot the resident flash application, unless someone wants us to stay
+async fn autoboot(stay: bool) {
+    Timer::after(Duration::from_millis(DOUBLE_TAP_MS)).await;
+    magic::set(&mut &MAGIC, magic::DOUBLE_TAP_IDX, 0);
+
+    if stay || AUTOBOOT_MS == 0 {
+        return;
+    }
+    Timer::after(Duration::from_millis(AUTOBOOT_MS.saturating_sub(DOUBLE_TAP_MS))).await;
+
+    if HOST_CONNECTED.load(Ordering::Relaxed) {
+        return;
+    }
+    match stage0().boot_slot() {
+        Some(addr) => {
+            s0log!(info, "Booting the flash application at {=u32:#X}", addr);
+            magic::arm_boot(&mut &MAGIC, addr);
+            interrupt::disable();
+            SCB::sys_reset();
+        }
+        None => s0log!(info, "No bootable flash application, staying in stage0"),
+    }
 }
 
 struct Disconnected {}
@@ -267,7 +363,12 @@ struct Disconnected {}
 impl From<EndpointError> for Disconnected {
     fn from(val: EndpointError) -> Self {
         match val {
-            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            // A packet too big for the endpoint is our bug, not a reason to
+            // reset. Start over with a fresh connection instead.
+            EndpointError::BufferOverflow => {
+                s0log!(error, "Buffer overflow writing a packet");
+                Disconnected {}
+            }
             EndpointError::Disabled => Disconnected {},
         }
     }
@@ -278,135 +379,41 @@ async fn acc<'d, 'acc, T: Instance + 'd, P: VbusDetect + 'd>(
 ) -> Result<(), Disconnected> {
     let mut buf = [0; 64];
     let mut outbuf = [0u8; 512];
+    let mut frames = Frames::<ACC_SIZE>::new();
+    let mut stage0 = stage0();
 
-    let mut acc = CobsAccumulator::<ACC_SIZE>::new();
     loop {
-        let n = class.read_packet(&mut buf).await?;
-        let mut window = &buf[..n];
-
-        'cobs: while !window.is_empty() {
-            use FeedResult::*;
-
-            window = match acc.feed_ref::<Request<'_>>(&window) {
-                Consumed => break 'cobs,
-                OverFull(new_wind) | DeserError(new_wind) => new_wind,
-                Success { data, remaining } => {
-                    let resp = req_handler(data, &mut outbuf);
-
-                    for ch in resp.chunks(64) {
-                        class.write_packet(ch).await?;
-                    }
+        let n = match class.read_packet(&mut buf).await {
+            Ok(n) => n,
+            // A packet we couldn't take is lost, the frame it was part of is useless
+            Err(EndpointError::BufferOverflow) => {
+                frames.lost();
+                continue;
+            }
+            Err(EndpointError::Disabled) => return Err(Disconnected {}),
+        };
 
-                    remaining
-                }
+        for &byte in &buf[..n] {
+            let Some((raw, broken)) = frames.push(byte) else {
+                continue;
             };
-        }
-    }
-}
-
-fn req_handler<'a>(req: Request<'_>, outbuf: &'a mut [u8]) -> &'a [u8] {
-    let mut membuf = [0u8; 256];
-
-    let resp: Result<Response<'_>, IcdError> = match req {
-        Request::PeekBytes { addr, len } => {
-            if len > membuf.len() {
-                Err(IcdError::RangeTooLarge {
-                    request: len,
-                    max: membuf.len(),
-                })
-            } else {
-                SCRATCH.read_to(addr, &mut membuf[..len])
-                    .map(|buf| {
-                        Response::PeekBytes(PeekBytes { addr, val: Managed::from_borrowed(buf) })
-                    })
+            match &broken {
+                Some(err) => s0log!(error, "Bad frame: {}", err),
+                None => HOST_CONNECTED.store(true, Ordering::Relaxed),
             }
-        },
-        Request::PokeBytes { addr, val } => {
-            SCRATCH.write_from(addr, val.as_slice())
-                .map(|()| {
-                    Response::Poked(Poked { addr })
-                })
-        },
-        Request::Bootload { addr } => {
-            // Validation is for suckers.
-            let mut scratch = [0u8; 8];
-            scratch[..4].copy_from_slice(&0x0FACADE0u32.to_ne_bytes());
-            scratch[4..].copy_from_slice(&addr.to_ne_bytes());
-            unsafe {
-                MAGIC.as_ptr().copy_from_nonoverlapping(scratch.as_ptr(), 8);
+            let (resp, after) = stage0.handle_frame(raw, broken, &mut outbuf);
+
+            for ch in resp.chunks(64) {
+                class.write_packet(ch).await?;
             }
 
-            // o7
-            interrupt::disable();
-            SCB::sys_reset();
-        },
-        Request::PeekBytesFlash { addr, len } => {
-            const MAX_ADDR: usize = 1024 * 1024;
-
-            if len > membuf.len() {
-                Err(IcdError::RangeTooLarge {
-                    request: len,
-                    max: membuf.len(),
-                })
-            } else if addr.saturating_add(len) > MAX_ADDR {
-                Err(IcdError::AddressOutOfRange { request: addr, len, min: 0, max: MAX_ADDR })
-            } else {
-                let slice = &mut membuf[..len];
-                let src = addr as *const u8;
-                slice.iter_mut().enumerate().for_each(|(i, b)| {
-                    *b = unsafe { src.add(i).read_volatile() };
-                });
-                Ok(Response::PeekBytesFlash(PeekBytes { addr: addr, val: Managed::from_borrowed(slice) }))
+            if let After::Reset = after {
+                // Give the host a moment to pick up the response. o7
+                Timer::after(Duration::from_millis(10)).await;
+                interrupt::disable();
+                SCB::sys_reset();
             }
         }
-        Request::ClearMagic => todo!(),
-        Request::Reboot => todo!(),
-        Request::FlashCopy { ram_start, flash_start, len } => {
-            SCRATCH.contains(ram_start, len).and_then(|ptr| {
-                if (flash_start & (4096 - 1)) != 0 {
-                    Err(IcdError::UnalignedFlashAddr(UnalignedFlashAddr { addr: flash_start, align: 4096 }))
-                } else if flash_start < (32 * 1024) {
-                    Err(IcdError::CantOverwriteBootloader)
-                } else {
-                    let mut nvmc = Nvmc::new(unsafe { NVMC::steal() });
-                    let mut idx = flash_start as u32;
-
-                    let slice = unsafe { core::slice::from_raw_parts(ptr, len) };
-                    let res = slice
-                        .chunks(Nvmc::ERASE_SIZE)
-                        .try_for_each(|ch| {
-                            nvmc.erase(idx, idx + (Nvmc::ERASE_SIZE as u32))?;
-                            let aligned_end = ch.len() & !(Nvmc::WRITE_SIZE - 1);
-                            let (aligned, unaligned) = ch.split_at(aligned_end);
-                            nvmc.write(idx, aligned)?;
-
-                            if !unaligned.is_empty() {
-                                let mut extra = [0xFF; Nvmc::WRITE_SIZE];
-                                extra[..unaligned.len()].copy_from_slice(unaligned);
-                                nvmc.write(idx + (aligned.len() as u32), &extra)?;
-                            }
-
-                            idx += ch.len() as u32;
-
-                            Result::<_, <Nvmc as ErrorType>::Error>::Ok(())
-                        });
-
-                    match res {
-                        Ok(_) => Ok(Response::FlashCopied),
-                        Err(_) => Err(IcdError::FlashCopyFailed),
-                    }
-
-                }
-            })
-        },
-    };
-
-    match postcard::to_slice_cobs(&resp, outbuf) {
-        Ok(ser) => ser,
-        Err(_) => {
-            s0log!(error, "Serialization went bad.");
-            &[0x00]
-        },
     }
 }
 
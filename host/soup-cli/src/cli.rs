@@ -7,6 +7,23 @@ pub struct Address(pub u32);
 #[derive(Debug, Clone)]
 pub struct WriteBytes(pub Vec<u8>);
 
+#[derive(Debug, Clone)]
+pub struct Arg(pub u32);
+
+#[derive(Debug, Clone)]
+pub struct AppVersion(pub soup_image::AppVersion);
+
+#[derive(Parser, Debug)]
+pub struct SoupCli {
+    /// Serial port of the device, like /dev/ttyACM0. Looked up by its USB
+    /// product name if not given
+    #[clap(long = "port", global = true)]
+    pub port: Option<String>,
+
+    #[clap(subcommand)]
+    pub cmd: Soup,
+}
+
 #[derive(Parser, Debug)]
 pub enum Soup {
     /// Reboot Application
@@ -18,12 +35,125 @@ pub enum Soup {
     /// Connect stdio (and err) to the console
     Stdio,
     /// Run
-    Run(Run)
+    Run(Run),
+    /// Application image commands
+    Image(ImageShim),
+    /// Generate a key pair for signing images
+    Keygen(Keygen),
+    /// Sign a packed image
+    Sign(Sign),
+    /// Convert a file to UF2 for USB mass storage bootloaders, or show what's
+    /// in a UF2 file
+    Uf2(Uf2),
+    /// Print every stage0 and soup app message type as JSON, for tooling
+    /// in other languages
+    Schema,
+    /// Simulate a soup device behind a pseudo-terminal, for use with `--port`
+    #[cfg(unix)]
+    Simulate(Simulate),
 }
 
 #[derive(Args, Debug)]
 pub struct Run {
+    /// File to load into RAM or flash and run: a packed image, ELF, Intel HEX, SREC,
+    /// UF2, or a raw binary with `--base`
+    pub elf_path: String,
+
+    /// Where a raw binary goes
+    #[clap(long = "base")]
+    pub base: Option<Address>,
+
+    /// Load an ELF file linked with its relocations kept here, instead of
+    /// where it's linked
+    #[clap(long = "at", conflicts_with = "base")]
+    pub at: Option<Address>,
+
+    /// Only load it, to `stage0 bootload` later
+    #[clap(long = "no-boot")]
+    pub no_boot: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ImageShim {
+    #[clap(subcommand)]
+    pub cmd: Image,
+}
+
+#[derive(Parser, Debug)]
+pub enum Image {
+    /// Pack an ELF file into an image with a header stage0 can check
+    Pack(Pack),
+    /// Show and check the header of a packed image
+    Show(ImageFile),
+    /// Write a packed image to flash
+    Flash(ImageFile),
+    /// Write a packed image to the inactive slot, and boot it on trial
+    Update(ImageFile),
+}
+
+#[derive(Args, Debug)]
+pub struct Pack {
     pub elf_path: String,
+
+    /// Output file. Defaults to the ELF path with a `.simg` extension
+    #[clap(short = 'o', long = "output")]
+    pub output: Option<String>,
+
+    /// Application version to record in the header, like "1.2.3"
+    #[clap(long = "app-version", default_value = "0.0.0")]
+    pub app_version: AppVersion,
+}
+
+#[derive(Args, Debug)]
+pub struct ImageFile {
+    pub path: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Uf2 {
+    /// ELF, Intel HEX, SREC, or a raw binary with `--base`. A UF2 file is
+    /// shown instead.
+    pub path: String,
+
+    /// Where a raw binary goes
+    #[clap(long = "base")]
+    pub base: Option<Address>,
+
+    /// Chip family, by name (nrf52840, nrf52) or ID
+    #[clap(long = "family", default_value = "nrf52840")]
+    pub family: String,
+
+    /// Output file. Defaults to the input path with a `.uf2` extension
+    #[clap(short = 'o', long = "output")]
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Keygen {
+    /// Writes the secret key to NAME.key, and the public key to NAME.pub
+    #[clap(default_value = "soup")]
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+pub struct Sign {
+    /// Packed image to sign
+    pub path: String,
+
+    /// Secret key from `keygen`
+    #[clap(short = 'k', long = "key")]
+    pub key: String,
+
+    /// Output file. Signs the image in place if not provided
+    #[clap(short = 'o', long = "output")]
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct Simulate {
+    /// A line the simulated app prints after each hello. Can be given more than once
+    #[clap(long = "stdout")]
+    pub stdout: Vec<String>,
 }
 
 #[derive(Args, Debug)]
@@ -44,6 +174,20 @@ pub enum Stage0 {
     FlashPoke(Poke),
     /// Reboot to loaded firmware
     Bootload(Bootload),
+    /// Reboot into stage0, the last bootloaded RAM image, or a flash image
+    Reboot(Reboot),
+    /// Forget the pending boot command and the last bootloaded image
+    ClearMagic,
+    /// Show the device's version and memory layout
+    Info,
+    /// Compare RAM or Flash against a local file, using a checksum calculated on the device
+    Verify(Verify),
+    /// Measure upload speed to scratch RAM. Overwrites whatever is loaded there!
+    Bench(Bench),
+    /// Show the A/B flash slots, and which one boots next
+    Slots,
+    /// Load an ELF file into RAM, and call a function in it without a reset
+    Call(Call),
 }
 
 #[derive(Args, Debug)]
@@ -63,14 +207,15 @@ pub struct Peek {
 
 #[derive(Args, Debug, Clone)]
 pub struct Poke {
-    /// The address to write to.
+    /// The address to write to. Not needed for files that say where they
+    /// go: ELF, Intel HEX, SREC and UF2.
     #[clap(short = 'a')]
-    pub address: Address,
+    pub address: Option<Address>,
     /// Bytes to write to the address. For example: "0xA0,0xAB,0x11".
     #[clap(short = 'b', long = "write")]
     pub val: Option<WriteBytes>,
 
-    /// Input file
+    /// Input file: ELF, Intel HEX, SREC, UF2, or a raw binary
     #[clap(short = 'f', long = "file")]
     pub file: Option<String>,
 }
@@ -82,6 +227,60 @@ pub struct Bootload {
     pub address: Address,
 }
 
+#[derive(Args, Debug)]
+pub struct Reboot {
+    /// Re-run the image last started with `bootload`, if it is still in RAM
+    #[clap(long = "ram", conflicts_with = "flash")]
+    pub ram: bool,
+
+    /// Boot the flash image with its vector table at this address
+    #[clap(long = "flash")]
+    pub flash: Option<Address>,
+}
+
+#[derive(Args, Debug)]
+pub struct Verify {
+    /// The address to compare at. Only needed for raw binaries.
+    #[clap(short = 'a')]
+    pub address: Option<Address>,
+
+    /// File to compare against: ELF, Intel HEX, SREC, UF2, or a raw binary
+    #[clap(short = 'f', long = "file")]
+    pub file: String,
+
+    /// Compare a SHA-256 digest instead of a CRC32
+    #[clap(long = "sha256")]
+    pub sha256: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Call {
+    /// ELF file holding the function, linked to run from scratch RAM
+    pub elf_path: String,
+
+    /// Name of the function to call
+    pub symbol: String,
+
+    /// Up to four arguments, passed in r0 to r3. Decimal, or hex with "0x"
+    #[clap(max_values = 4)]
+    pub args: Vec<Arg>,
+
+    /// Don't load the ELF file first, it's still in RAM from an earlier call
+    #[clap(long = "no-load")]
+    pub no_load: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct Bench {
+    /// How many bytes to upload. Defaults to the whole scratch area
+    #[clap(short = 's', long = "size")]
+    pub size: Option<usize>,
+
+    /// Wait for every chunk to be acknowledged, instead of using windows
+    #[clap(long = "acked")]
+    pub acked: bool,
+}
+
 impl FromStr for WriteBytes {
     type Err = ParseIntError;
 
@@ -97,6 +296,34 @@ impl FromStr for WriteBytes {
     }
 }
 
+impl FromStr for AppVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split('.')
+            .map(u16::from_str)
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|e| e.to_string())?;
+
+        match parts[..] {
+            [major, minor, patch] => Ok(Self(soup_image::AppVersion { major, minor, patch })),
+            _ => Err(format!("expected MAJOR.MINOR.PATCH, got {s:?}")),
+        }
+    }
+}
+
+impl FromStr for Arg {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).map(Self),
+            None => u32::from_str(s).map(Self),
+        }
+    }
+}
+
 impl FromStr for Address {
     type Err = ParseIntError;
 
@@ -1,23 +1,20 @@
 use object::{
-    elf::{FileHeader32, PT_LOAD},
-    read::elf::{FileHeader, ProgramHeader},
-    LittleEndian, Object, ObjectSection,
+    elf::*,
+    read::elf::{FileHeader, ProgramHeader, Rel, SectionHeader, Sym},
+    LittleEndian, Object, ObjectSection, ObjectSymbol, SectionIndex, SymbolKind,
 };
 use std::{
-    cmp::Ordering,
+    collections::BTreeSet,
     error::Error,
     fs,
     ops::Range,
 };
 
-pub struct Loadable {
-    pub addr: u32,
-    pub data: Vec<u8>,
-}
+use crate::load::Segment;
 
-pub fn parse_loadable(s: String) -> Result<Loadable, Box<dyn Error>> {
-    let bin_data = fs::read(&s)?;
-    let obj_file = object::File::parse(&*bin_data)?;
+/// The loadable segments of a 32-bit little endian ELF file
+pub fn segments(bin_data: &[u8]) -> Result<Vec<Segment>, Box<dyn Error>> {
+    let obj_file = object::File::parse(bin_data)?;
 
     match obj_file.format() {
         object::BinaryFormat::Elf => {}
@@ -28,94 +25,219 @@ pub fn parse_loadable(s: String) -> Result<Loadable, Box<dyn Error>> {
         return Err("Only LE supported".into());
     }
 
-    let file_kind = object::FileKind::parse(&*bin_data)?;
+    let file_kind = object::FileKind::parse(bin_data)?;
 
     match file_kind {
         object::FileKind::Elf32 => {}
         fk => return Err(format!("Unsupported file type: {:?}", fk).into()),
     }
 
-    let elf_header = FileHeader32::<LittleEndian>::parse(&*bin_data)?;
+    let elf_header = FileHeader32::<LittleEndian>::parse(bin_data)?;
     let endian = elf_header.endian()?;
 
-    let mut lowest_addr = u64::MAX;
-    let mut highest_addr = u64::MIN;
-    let mut bin_contents = vec![];
+    if elf_header.e_type(endian) == ET_REL {
+        return Err("This is an object file, it needs linking first".into());
+    }
+
+    let mut segments = vec![];
 
     // NOTE: Using https://github.com/probe-rs/probe-rs/blob/5a29e83847118c3999a2ca0ab017f080719b8ae5/probe-rs/src/flashing/download.rs#L194
     // as a reference
-    for segment in elf_header.program_headers(endian, &*bin_data)? {
-        let p_paddr: u64 = segment.p_paddr(endian).into();
-        let _p_vaddr: u64 = segment.p_vaddr(endian).into();
-        let _flags = segment.p_flags(endian);
+    for segment in elf_header.program_headers(endian, bin_data)? {
+        if segment.p_type(endian) != PT_LOAD {
+            continue;
+        }
 
-        let segment_data = segment.data(endian, &*bin_data).map_err(|_| {
+        let p_paddr: u64 = segment.p_paddr(endian).into();
+        let segment_data = segment.data(endian, bin_data).map_err(|_| {
             "Failed to get segment data"
         })?;
 
-        let load = segment.p_type(endian) == PT_LOAD;
-        let _sz = segment_data.len();
-
-        // println!("{p_paddr:08X}, {p_vaddr:08X}, {flags:08X}, sz: {sz}, l?: {load}");
-
-        if !load {
+        // NOLOAD sections and `.bss` take up memory, but nothing in the file
+        if segment_data.is_empty() {
             continue;
         }
 
         let (segment_offset, segment_filesize) = segment.file_range(endian);
-
         let sector: core::ops::Range<u64> = segment_offset..segment_offset + segment_filesize;
 
+        // Only segments holding sections are part of the program
+        let mut has_sections = false;
         for section in obj_file.sections() {
             let (section_offset, section_filesize) = match section.file_range() {
                 Some(range) => range,
                 None => continue,
             };
+            if !sector.contains_range(&(section_offset..section_offset + section_filesize)) {
+                continue;
+            }
+            has_sections = true;
+        }
+
+        if has_sections {
+            segments.push(Segment { addr: p_paddr.try_into()?, data: segment_data.to_vec() });
+        }
+    }
+
+    if segments.is_empty() {
+        return Err("No sections found?".into());
+    }
+
+    Ok(segments)
+}
+
+/// Where a PT_LOAD segment goes in memory
+pub struct LoadRange {
+    /// Where it's loaded
+    pub lma: u32,
+    /// Where it runs from
+    pub vma: u32,
+    /// Its size in memory, `.bss` included
+    pub len: u32,
+}
+
+/// The memory every PT_LOAD segment takes up, including the ones with
+/// nothing in the file
+pub fn load_ranges(bin_data: &[u8]) -> Result<Vec<LoadRange>, Box<dyn Error>> {
+    let elf_header = FileHeader32::<LittleEndian>::parse(bin_data)?;
+    let endian = elf_header.endian()?;
+
+    Ok(elf_header
+        .program_headers(endian, bin_data)?
+        .iter()
+        .filter(|ph| ph.p_type(endian) == PT_LOAD && ph.p_memsz(endian) != 0)
+        .map(|ph| LoadRange { lma: ph.p_paddr(endian), vma: ph.p_vaddr(endian), len: ph.p_memsz(endian) })
+        .collect())
+}
+
+/// `bin_data`, with the fix-ups to run everything `delta` bytes from where
+/// it's linked applied, and how many there were. That takes an ELF file
+/// that kept its relocations, from `--emit-relocs` or as position
+/// independent code. Fix-ups are found by where the code runs from, so it
+/// has to be loaded there too.
+pub fn relocate(bin_data: &[u8], delta: u32) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
+    let elf_header = FileHeader32::<LittleEndian>::parse(bin_data)?;
+    let endian = elf_header.endian()?;
+    let sections = elf_header.sections(endian, bin_data)?;
+    let program_headers = elf_header.program_headers(endian, bin_data)?;
+
+    // Where the word at `addr` is in the file
+    let file_offset = |addr: u32| {
+        program_headers.iter().filter(|ph| ph.p_type(endian) == PT_LOAD).find_map(|ph| {
+            let offset = addr.checked_sub(ph.p_vaddr(endian))?;
+            (offset + 4 <= ph.p_filesz(endian)).then(|| (ph.p_offset(endian) + offset) as usize)
+        })
+    };
 
-            if sector.contains_range(&(section_offset..section_offset + section_filesize)) {
-                // println!("  -> Matching section: {:?}", section.name()?);
-                // println!("  -> {:08X}, {}", p_paddr, segment_data.len());
-
-                lowest_addr = lowest_addr.min(p_paddr);
-                let fsz: u32 = segment.p_filesz(endian);
-                let fsz64: u64 = fsz.into();
-                assert_eq!(segment_data.len(), fsz.try_into()?);
-
-                highest_addr = highest_addr.max(p_paddr + fsz64);
-                bin_contents.push((p_paddr, segment_data));
-
-                for (offset, relocation) in section.relocations() {
-                    return Err(format!(
-                        "I can't do relocations sorry: ({}) {:?}, {:?}",
-                        section.name()?,
-                        offset,
-                        relocation,
-                    ).into());
+    let mut out = bin_data.to_vec();
+    let mut tables = 0;
+    // A position independent file linked with `--emit-relocs` has both a
+    // dynamic relocation and the original one for the same word
+    let mut fixed = BTreeSet::new();
+    for section in sections.iter() {
+        if section.sh_type(endian) == SHT_RELA {
+            return Err("RELA relocations aren't used on ARM, is this an ARM ELF file?".into());
+        }
+        let Some((rels, link)) = section.rel(endian, bin_data)? else {
+            continue;
+        };
+
+        // Debug info and the like aren't loaded, their relocations don't matter
+        let target = section.sh_info(endian) as usize;
+        if target != 0 && sections.section(SectionIndex(target))?.sh_flags(endian) & SHF_ALLOC == 0 {
+            continue;
+        }
+        tables += 1;
+
+        let symbols = match link.0 {
+            0 => None,
+            _ => Some(sections.symbol_table_by_index(endian, bin_data, link)?),
+        };
+        // Symbols in a section move along, absolute and undefined ones don't
+        let moves = |sym: u32| -> Result<bool, Box<dyn Error>> {
+            let Some(symbols) = &symbols else {
+                return Ok(false);
+            };
+            let shndx = symbols.symbol(sym as usize)?.st_shndx(endian);
+            Ok(sym != 0 && shndx != SHN_UNDEF && shndx < SHN_LORESERVE)
+        };
+
+        for rel in rels {
+            let (addr, r_type) = (rel.r_offset(endian), rel.r_type(endian));
+            let fixup = match r_type {
+                R_ARM_ABS32 | R_ARM_TARGET1 => moves(rel.r_sym(endian))?,
+                R_ARM_RELATIVE => true,
+                // Relative to the PC or the GOT, which move along with the
+                // code, as long as what they point to does too
+                R_ARM_REL32 | R_ARM_THM_PC22 | R_ARM_THM_JUMP24 | R_ARM_THM_JUMP19 | R_ARM_THM_PC11
+                | R_ARM_THM_PC9 | R_ARM_THM_PC8 | R_ARM_THM_PC12 | R_ARM_THM_ALU_PREL_11_0 | R_ARM_PREL31
+                | R_ARM_THM_MOVW_PREL_NC | R_ARM_THM_MOVT_PREL | R_ARM_CALL | R_ARM_JUMP24 | R_ARM_GOT_PREL
+                | R_ARM_GOTOFF | R_ARM_GOTPC | R_ARM_GOT32 => {
+                    if rel.r_sym(endian) != 0 && !moves(rel.r_sym(endian))? {
+                        return Err(format!("A relative relocation at 0x{addr:08X} points somewhere that doesn't move").into());
+                    }
+                    false
                 }
+                R_ARM_NONE | R_ARM_V4BX => false,
+                _ => return Err(format!("I can't move relocation type {r_type} at 0x{addr:08X}, only 32 bit absolute and relative ones").into()),
+            };
+            if !fixup || !fixed.insert(addr) {
+                continue;
             }
+
+            let offset = file_offset(addr).ok_or_else(|| format!("The relocation at 0x{addr:08X} isn't in the file"))?;
+            let word = u32::from_le_bytes(out[offset..][..4].try_into()?);
+            out[offset..][..4].copy_from_slice(&word.wrapping_add(delta).to_le_bytes());
         }
     }
 
-    match lowest_addr.cmp(&highest_addr) {
-        Ordering::Less => {}
-        Ordering::Equal => return Err("Empty file?".into()),
-        Ordering::Greater if bin_contents.is_empty() => return Err("No sections found?".into()),
-        Ordering::Greater => return Err("Start is after end?".into()),
+    if tables == 0 {
+        return Err("No relocations to move it with, link it with --emit-relocs".into());
+    }
+
+    Ok((out, fixed.len()))
+}
+
+/// Where the vector table is, for VTOR. cortex-m-rt gives it a section of
+/// its own, and `__RESET_VECTOR` is its second word. Without either, look
+/// for a table whose reset vector is the entry point.
+pub fn vector_table(bin_data: &[u8], segments: &[Segment]) -> Result<Option<u32>, Box<dyn Error>> {
+    let obj_file = object::File::parse(bin_data)?;
+
+    if let Some(section) = obj_file.section_by_name(".vector_table") {
+        return Ok(Some(section.address().try_into()?));
+    }
+    if let Some(symbol) = obj_file.symbols().find(|sym| sym.name() == Ok("__RESET_VECTOR")) {
+        return Ok(Some(u32::try_from(symbol.address())? - 4));
+    }
+
+    let entry = u32::try_from(obj_file.entry())? | 1;
+    if entry == 1 {
+        return Ok(None);
     }
+    Ok(segments.iter().find_map(|seg| {
+        (seg.addr.next_multiple_of(VTOR_ALIGN)..seg.end()).step_by(VTOR_ALIGN as usize).find(|addr| seg.word(addr + 4) == Some(entry))
+    }))
+}
+
+/// The smallest alignment VTOR takes on any Cortex-M
+pub const VTOR_ALIGN: u32 = 128;
 
-    let ttl_len: usize = (highest_addr - lowest_addr).try_into()?;
-    // println!("start: 0x{lowest_addr:08X}");
-    // println!("end:   0x{highest_addr:08X}");
-    // println!("size:  {ttl_len}");
+/// The address of the function `name` in the ELF file at `path`, with the
+/// Thumb bit set
+pub fn function_addr(path: &str, name: &str) -> Result<u32, Box<dyn Error>> {
+    let bin_data = fs::read(path)?;
+    let obj_file = object::File::parse(&*bin_data)?;
 
-    let mut output = vec![0x00u8; ttl_len];
-    for (addr, data) in bin_contents.iter() {
-        let adj_addr = (addr - lowest_addr).try_into()?;
-        let size = data.len();
-        output[adj_addr..][..size].copy_from_slice(data);
+    let symbol = obj_file
+        .symbols()
+        .find(|sym| sym.name() == Ok(name))
+        .ok_or_else(|| format!("{path} has no symbol named {name:?}"))?;
+    if symbol.kind() != SymbolKind::Text {
+        return Err(format!("{name} isn't a function, it's {:?}", symbol.kind()).into());
     }
 
-    Ok(Loadable { addr: lowest_addr.try_into()?, data: output })
+    Ok(u32::try_from(symbol.address())? | 1)
 }
 
 ///////
@@ -125,9 +247,6 @@ pub fn parse_loadable(s: String) -> Result<Loadable, Box<dyn Error>> {
 pub trait MemoryRange {
     /// Returns true if `self` contains `range` fully.
     fn contains_range(&self, range: &Range<u64>) -> bool;
-
-    /// Returns true if `self` intersects `range` partially.
-    fn intersects_range(&self, range: &Range<u64>) -> bool;
 }
 
 impl MemoryRange for Range<u64> {
@@ -138,15 +257,4 @@ impl MemoryRange for Range<u64> {
             self.contains(&range.start) && self.contains(&(range.end - 1))
         }
     }
-
-    fn intersects_range(&self, range: &Range<u64>) -> bool {
-        if range.end == 0 {
-            false
-        } else {
-            self.contains(&range.start) && !self.contains(&(range.end - 1))
-                || !self.contains(&range.start) && self.contains(&(range.end - 1))
-                || self.contains_range(range)
-                || range.contains_range(self)
-        }
-    }
 }
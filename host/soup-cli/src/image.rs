@@ -0,0 +1,137 @@
+//! Packed application images, a `soup_image` header followed by the image,
+//! and optionally a signature
+
+use std::{error::Error, fs, io::Read, path::Path};
+
+use soup_image::{signature, ImageHeader, Signature, HEADER_SIZE};
+
+use crate::{
+    cli::{Keygen, Pack, Sign},
+    load::{self, parse_loadable, Segment},
+};
+
+/// A packed image file
+pub struct Packed {
+    pub header: ImageHeader,
+    pub image: Vec<u8>,
+    pub signature: Option<Signature>,
+}
+
+impl Packed {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.header.to_bytes().to_vec();
+        out.extend_from_slice(&self.image);
+        if let Some(sig) = &self.signature {
+            out.extend_from_slice(&sig.to_bytes());
+        }
+        out
+    }
+}
+
+pub fn pack(cmd: Pack) -> Result<(), Box<dyn Error>> {
+    let load = parse_loadable(&cmd.elf_path, None, 0x00)?;
+
+    // Without a vector table in the file, assume it's first, like cortex-m-rt
+    // puts it
+    let segments = [Segment { addr: load.addr, data: load.data }];
+    let entry = load::vector_table(&cmd.elf_path, &segments)?.unwrap_or(load.addr);
+    if let Err(e) = load::check_vector_table(&segments, entry) {
+        println!(" -> Warning: {e}, stage0 won't boot this");
+    }
+    let [Segment { data, .. }] = segments;
+
+    let header = ImageHeader::new(load.addr, entry, cmd.app_version.0, &data);
+    let packed = Packed { header, image: data, signature: None };
+
+    let output = match cmd.output {
+        Some(output) => output,
+        None => Path::new(&cmd.elf_path)
+            .with_extension("simg")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    fs::write(&output, packed.to_bytes())?;
+
+    println!(
+        " -> Packed {} bytes for 0x{:08X}, version {}, into {output}",
+        header.image_len, header.load_addr, header.app_version
+    );
+
+    Ok(())
+}
+
+/// Does the file at `path` start with the image header magic?
+pub fn is_image(path: &str) -> Result<bool, Box<dyn Error>> {
+    let mut magic = [0u8; 4];
+    let mut file = fs::File::open(path)?;
+    Ok(file.read_exact(&mut magic).is_ok() && u32::from_le_bytes(magic) == soup_image::MAGIC)
+}
+
+/// Read a packed image, and make sure it's intact
+pub fn read_image(path: &str) -> Result<Packed, Box<dyn Error>> {
+    let file = fs::read(path)?;
+    let header = ImageHeader::from_bytes(&file).map_err(|e| format!("{path}: {e}"))?;
+
+    let mut image = file[HEADER_SIZE..].to_vec();
+    header.check_image(&image).map_err(|e| format!("{path}: {e}"))?;
+    let rest = image.split_off(header.image_len as usize);
+
+    let signature = match rest.len() {
+        0 => None,
+        Signature::SIZE => Some(Signature::from_bytes(rest[..].try_into()?)),
+        n => return Err(format!("{path}: {n} unexpected bytes after the image").into()),
+    };
+
+    Ok(Packed { header, image, signature })
+}
+
+pub fn show(path: &str) -> Result<(), Box<dyn Error>> {
+    let packed = read_image(path)?;
+    let header = &packed.header;
+
+    println!("Header version: {}", header.header_version);
+    println!("App version:    {}", header.app_version);
+    println!("Image length:   {} bytes", header.image_len);
+    println!("Load address:   0x{:08X}", header.load_addr);
+    println!("Entry:          0x{:08X}", header.entry);
+    println!("Image CRC:      0x{:08X}", header.image_crc);
+    println!("Signed:         {}", if packed.signature.is_some() { "yes" } else { "no" });
+    println!(" -> Header and image are intact.");
+
+    Ok(())
+}
+
+pub fn keygen(cmd: Keygen) -> Result<(), Box<dyn Error>> {
+    let secret_path = format!("{}.key", cmd.name);
+    let public_path = format!("{}.pub", cmd.name);
+    if Path::new(&secret_path).exists() {
+        return Err(format!("{secret_path} already exists, refusing to overwrite a secret key").into());
+    }
+
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed)?;
+    let public = signature::public_key(&seed);
+
+    fs::write(&secret_path, seed)?;
+    fs::write(&public_path, public)?;
+
+    println!(" -> Secret key: {secret_path}, keep it safe!");
+    println!(" -> Public key: {public_path}, build stage0 with STAGE0_PUBLIC_KEY pointing at it");
+
+    Ok(())
+}
+
+pub fn sign(cmd: Sign) -> Result<(), Box<dyn Error>> {
+    let mut packed = read_image(&cmd.path)?;
+    let seed: [u8; 32] = fs::read(&cmd.key)?
+        .try_into()
+        .map_err(|_| format!("{} isn't a 32 byte secret key", cmd.key))?;
+
+    packed.signature = Some(signature::sign(&seed, &packed.header, &packed.image));
+
+    let output = cmd.output.unwrap_or(cmd.path);
+    fs::write(&output, packed.to_bytes())?;
+    println!(" -> Signed {output}");
+
+    Ok(())
+}
@@ -0,0 +1,483 @@
+//! Reading what to load from a file: ELF, Intel HEX, Motorola SREC, UF2, or
+//! a raw binary with a base address. They all come out as the same list of
+//! segments.
+
+use std::{collections::BTreeMap, error::Error, fmt, fs};
+
+use stage0_icd::MemRange;
+
+use crate::{elf, uf2};
+
+/// Segments further apart than this aren't flattened into one piece
+const MAX_SPAN: u64 = 16 * 1024 * 1024;
+
+/// Bytes, and the address they go to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.addr.saturating_add(self.data.len() as u32)
+    }
+
+    /// The little endian word at `addr`, if it's all in this segment
+    pub fn word(&self, addr: u32) -> Option<u32> {
+        let offset = addr.checked_sub(self.addr)? as usize;
+        Some(u32::from_le_bytes(self.data.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+    }
+}
+
+/// Every segment of a file in one piece, from its lowest address to the end
+/// of its highest segment
+pub struct Loadable {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Elf,
+    Hex,
+    Srec,
+    Uf2,
+    Bin,
+}
+
+impl Format {
+    /// Figure out the format from the contents. Anything that isn't one of
+    /// the others is a raw binary.
+    pub fn detect(data: &[u8]) -> Self {
+        let text = data.is_ascii();
+        match data {
+            [0x7F, b'E', b'L', b'F', ..] => Format::Elf,
+            _ if uf2::is_uf2(data) => Format::Uf2,
+            [b':', ..] if text => Format::Hex,
+            [b'S', b'0'..=b'9', ..] if text => Format::Srec,
+            _ => Format::Bin,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Elf => "an ELF file",
+            Format::Hex => "an Intel HEX file",
+            Format::Srec => "an SREC file",
+            Format::Uf2 => "a UF2 file",
+            Format::Bin => "a raw binary",
+        })
+    }
+}
+
+/// The segments in the file at `path`, sorted by address. `base` is where a
+/// raw binary goes, every other format says that itself.
+pub fn segments(path: &str, base: Option<u32>) -> Result<Vec<Segment>, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    let format = Format::detect(&data);
+
+    let segments = match (format, base) {
+        (Format::Bin, Some(addr)) => vec![Segment { addr, data }],
+        (Format::Bin, None) => return Err(format!("{path} is a raw binary, it needs a base address").into()),
+        (_, Some(_)) => return Err(format!("{path} is {format}, which says where it goes, so it takes no base address").into()),
+        (Format::Elf, None) => elf::segments(&data)?,
+        (Format::Hex, None) => hex(std::str::from_utf8(&data)?)?,
+        (Format::Srec, None) => srec(std::str::from_utf8(&data)?)?,
+        (Format::Uf2, None) => uf2::segments(&data)?,
+    };
+
+    merge(segments).map_err(|e| format!("{path}: {e}").into())
+}
+
+/// Where the vector table in the file at `path` is, if it says. Only ELF
+/// files do.
+pub fn vector_table(path: &str, segments: &[Segment]) -> Result<Option<u32>, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    match Format::detect(&data) {
+        Format::Elf => elf::vector_table(&data, segments).map_err(|e| format!("{path}: {e}").into()),
+        _ => Ok(None),
+    }
+}
+
+/// The segments of the ELF file at `path`, moved to start at `at`, and its
+/// vector table there. Only apps that run from `scratch`, where they're
+/// loaded, can be moved, and only to somewhere they fit in it.
+pub fn relocated(path: &str, at: u32, scratch: MemRange) -> Result<(Vec<Segment>, Option<u32>), Box<dyn Error>> {
+    let data = fs::read(path)?;
+    let format = Format::detect(&data);
+    if format != Format::Elf {
+        return Err(format!("{path} is {format}, only ELF files can be moved").into());
+    }
+
+    let ranges = elf::load_ranges(&data).map_err(|e| format!("{path}: {e}"))?;
+    for range in &ranges {
+        let (vma, end) = (range.vma, u64::from(range.vma) + u64::from(range.len));
+        if range.lma != vma {
+            return Err(format!(
+                "{path}: 0x{vma:08X}..0x{end:08X} runs somewhere else than 0x{:08X}, where it's loaded, so it can't be moved",
+                range.lma
+            )
+            .into());
+        }
+        if !scratch.contains(vma as usize, range.len as usize) {
+            return Err(format!(
+                "{path}: 0x{vma:08X}..0x{end:08X} isn't in scratch RAM (0x{:08X}..0x{:08X}), only apps that run from there can be moved",
+                scratch.start,
+                scratch.end()
+            )
+            .into());
+        }
+    }
+    let (Some(start), Some(end)) = (
+        ranges.iter().map(|r| r.vma).min(),
+        ranges.iter().map(|r| r.vma as usize + r.len as usize).max(),
+    ) else {
+        return Err(format!("{path} has nothing to load").into());
+    };
+    if !scratch.contains(at as usize, end - start as usize) {
+        return Err(format!(
+            "{path} takes up {} bytes, which don't fit in scratch RAM (0x{:08X}..0x{:08X}) at 0x{at:08X}",
+            end - start as usize,
+            scratch.start,
+            scratch.end()
+        )
+        .into());
+    }
+
+    let linked = merge(elf::segments(&data)?).map_err(|e| format!("{path}: {e}"))?;
+    let vector_table = elf::vector_table(&data, &linked).map_err(|e| format!("{path}: {e}"))?;
+    let delta = at.wrapping_sub(start);
+
+    let (moved, fixups) = elf::relocate(&data, delta).map_err(|e| format!("{path}: {e}"))?;
+    println!(" -> Moving 0x{start:08X} to 0x{at:08X}, {fixups} fix-ups");
+    let segments = elf::segments(&moved)?
+        .into_iter()
+        .map(|seg| Segment { addr: seg.addr.wrapping_add(delta), data: seg.data })
+        .collect();
+
+    Ok((merge(segments)?, vector_table.map(|addr| addr.wrapping_add(delta))))
+}
+
+/// What's wrong with the vector table `segments` put at `addr`, if anything
+pub fn check_vector_table(segments: &[Segment], addr: u32) -> Result<(), String> {
+    let word = |addr| segments.iter().find_map(|seg| seg.word(addr));
+    let (Some(sp), Some(reset)) = (word(addr), word(addr + 4)) else {
+        return Err(format!("nothing is loaded at 0x{addr:08X} to be a vector table"));
+    };
+
+    if !addr.is_multiple_of(elf::VTOR_ALIGN) {
+        return Err(format!("0x{addr:08X} isn't aligned enough for VTOR"));
+    }
+    if sp == 0 || !sp.is_multiple_of(4) {
+        return Err(format!("0x{sp:08X} at 0x{addr:08X} doesn't look like an initial stack pointer"));
+    }
+    if reset & 1 == 0 || !segments.iter().any(|seg| (seg.addr..seg.end()).contains(&(reset & !1))) {
+        return Err(format!("0x{reset:08X} at 0x{:08X} isn't a Thumb function in the file, like a reset vector", addr + 4));
+    }
+
+    Ok(())
+}
+
+/// Everything in the file at `path` in one piece, with the gaps between
+/// segments filled with `fill`
+pub fn parse_loadable(path: &str, base: Option<u32>, fill: u8) -> Result<Loadable, Box<dyn Error>> {
+    let segments = segments(path, base)?;
+    let (first, last) = match (segments.first(), segments.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(format!("{path} has nothing to load").into()),
+    };
+
+    let start = first.addr;
+    let end = u64::from(last.addr) + last.data.len() as u64;
+    if end - u64::from(start) > MAX_SPAN {
+        return Err(format!("{path} spans 0x{start:08X}..0x{end:08X}, which is too far apart to load in one piece").into());
+    }
+
+    let mut data = vec![fill; (end - u64::from(start)) as usize];
+    for seg in &segments {
+        data[(seg.addr - start) as usize..][..seg.data.len()].copy_from_slice(&seg.data);
+    }
+
+    Ok(Loadable { addr: start, data })
+}
+
+/// The pages of flash `segments` touch, with what the segments don't cover
+/// left erased, since copying to flash erases whole pages. Pages that are
+/// back to back are joined, up to `max_len` bytes.
+pub fn flash_pages(segments: &[Segment], page_size: usize, max_len: usize) -> Vec<Segment> {
+    let mut pages: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+    for seg in segments {
+        for (i, byte) in seg.data.iter().enumerate() {
+            let addr = seg.addr as usize + i;
+            let page = pages.entry(addr - addr % page_size).or_insert_with(|| vec![0xFF; page_size]);
+            page[addr % page_size] = *byte;
+        }
+    }
+
+    let mut joined: Vec<Segment> = vec![];
+    for (addr, page) in pages {
+        match joined.last_mut() {
+            Some(last) if last.addr as usize + last.data.len() == addr && last.data.len() + page_size <= max_len => {
+                last.data.extend(page)
+            }
+            _ => joined.push(Segment { addr: addr as u32, data: page }),
+        }
+    }
+    joined
+}
+
+/// Sort `segments`, and join the ones that are back to back. Overlaps are
+/// an error, there's no telling which one is meant.
+pub fn merge(mut segments: Vec<Segment>) -> Result<Vec<Segment>, String> {
+    segments.retain(|seg| !seg.data.is_empty());
+    segments.sort_by_key(|seg| seg.addr);
+
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for seg in segments {
+        let end = seg.addr as u64 + seg.data.len() as u64;
+        if end > 1 << 32 {
+            return Err(format!("{} bytes at 0x{:08X} run past the end of memory", seg.data.len(), seg.addr));
+        }
+
+        if let Some(last) = merged.last_mut() {
+            let last_end = last.addr as u64 + last.data.len() as u64;
+            if u64::from(seg.addr) < last_end {
+                return Err(format!("0x{:08X}..0x{last_end:08X} overlaps with 0x{:08X}..0x{end:08X}", last.addr, seg.addr));
+            }
+            if u64::from(seg.addr) == last_end {
+                last.data.extend(seg.data);
+                continue;
+            }
+        }
+        merged.push(seg);
+    }
+
+    Ok(merged)
+}
+
+/// The data records of an Intel HEX file
+fn hex(text: &str) -> Result<Vec<Segment>, Box<dyn Error>> {
+    use ihex::Record;
+
+    let mut segments = vec![];
+    let mut base = 0u32;
+    for record in ihex::Reader::new(text) {
+        match record? {
+            Record::Data { offset, value } => segments.push(Segment { addr: base + u32::from(offset), data: value }),
+            Record::ExtendedSegmentAddress(segment) => base = u32::from(segment) << 4,
+            Record::ExtendedLinearAddress(upper) => base = u32::from(upper) << 16,
+            // Where execution starts, stage0 gets that from the vector table
+            Record::StartSegmentAddress { .. } | Record::StartLinearAddress(_) => {}
+            Record::EndOfFile => break,
+        }
+    }
+
+    Ok(segments)
+}
+
+/// The data records of a Motorola SREC file
+fn srec(text: &str) -> Result<Vec<Segment>, Box<dyn Error>> {
+    let mut segments = vec![];
+
+    for (i, line) in text.lines().map(str::trim).enumerate().filter(|(_, l)| !l.is_empty()) {
+        let lineno = i + 1;
+        let (kind, digits) = match line.as_bytes() {
+            [b'S', kind, digits @ ..] if digits.len() % 2 == 0 => (*kind, digits),
+            _ => return Err(format!("Line {lineno} isn't an S-record").into()),
+        };
+        let bytes = digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap_or("-"), 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("Line {lineno} has a bad hex digit"))?;
+
+        // The count covers the address, the data and the checksum
+        let (count, rest) = bytes.split_first().ok_or_else(|| format!("Line {lineno} is cut off"))?;
+        if usize::from(*count) != rest.len() || rest.is_empty() {
+            return Err(format!("Line {lineno} has {} bytes, but says {count}", rest.len()).into());
+        }
+        let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(format!("Line {lineno} has a bad checksum").into());
+        }
+
+        let addr_len = match kind {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            // The header, record counts, and where execution starts
+            b'0' | b'5' | b'6' | b'7' | b'8' | b'9' => continue,
+            _ => return Err(format!("Line {lineno} is an unknown S{} record", kind as char).into()),
+        };
+        let record = &rest[..rest.len() - 1];
+        if record.len() < addr_len {
+            return Err(format!("Line {lineno} is too short for its address").into());
+        }
+        let (addr, data) = record.split_at(addr_len);
+        let addr = addr.iter().fold(0u32, |addr, b| addr << 8 | u32::from(*b));
+        segments.push(Segment { addr, data: data.to_vec() });
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Two segments with a gap between them, and a record boundary in the first
+    fn expected() -> Vec<Segment> {
+        vec![
+            Segment { addr: 0x0001_FFF0, data: (0..32).collect() },
+            Segment { addr: 0x0003_0000, data: vec![0xAA, 0xBB, 0xCC] },
+        ]
+    }
+
+    fn srec_line(kind: u8, addr: u32, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8 + 5];
+        bytes.extend(addr.to_be_bytes());
+        bytes.extend(data);
+        bytes.push(!bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)));
+        let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+        format!("S{}{digits}\r\n", kind as char)
+    }
+
+    fn uf2_block(addr: u32, data: &[u8], flags: u32) -> Vec<u8> {
+        let mut block = vec![0u8; uf2::BLOCK_SIZE];
+        let words = [0x0A32_4655, 0x9E5D_5157, flags, addr, data.len() as u32, 0, 1, 0];
+        words.iter().enumerate().for_each(|(i, w)| block[i * 4..][..4].copy_from_slice(&w.to_le_bytes()));
+        block[32..][..data.len()].copy_from_slice(data);
+        block[508..].copy_from_slice(&0x0AB1_6F30u32.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn hex() {
+        // Crosses a 64 KiB boundary, so it needs extended addresses
+        let text = "\
+            :020000040001F9\n\
+            :10FFF000000102030405060708090A0B0C0D0E0F89\n\
+            :020000040002F8\n\
+            :10000000101112131415161718191A1B1C1D1E1F78\n\
+            :020000040003F7\n\
+            :03000000AABBCCCC\n\
+            :0400000500000101F5\n\
+            :00000001FF\n";
+        assert_eq!(Format::detect(text.as_bytes()), Format::Hex);
+        assert_eq!(merge(super::hex(text).unwrap()).unwrap(), expected());
+        assert!(super::hex(":10FFF000000102030405060708090A0B0C0D0E0F00\n").is_err());
+    }
+
+    #[test]
+    fn srec() {
+        let text = [
+            "S00600004844521B\n".to_string(),
+            srec_line(b'3', 0x0001_FFF0, &(0..16).collect::<Vec<u8>>()),
+            srec_line(b'3', 0x0002_0000, &(16..32).collect::<Vec<u8>>()),
+            srec_line(b'3', 0x0003_0000, &[0xAA, 0xBB, 0xCC]),
+            srec_line(b'7', 0x0002_0000, &[]),
+        ]
+        .concat();
+        assert_eq!(Format::detect(text.as_bytes()), Format::Srec);
+        assert_eq!(merge(super::srec(&text).unwrap()).unwrap(), expected());
+
+        // S1 and S2 have shorter addresses
+        let short = "S1050010ABCD72\nS206010000EF0009\n";
+        let segments = super::srec(short).unwrap();
+        assert_eq!(segments[0], Segment { addr: 0x0010, data: vec![0xAB, 0xCD] });
+        assert_eq!(segments[1], Segment { addr: 0x01_0000, data: vec![0xEF, 0x00] });
+
+        assert!(super::srec("S1050010ABCD73\n").is_err());
+        assert!(super::srec("S10600100000\n").is_err());
+    }
+
+    #[test]
+    fn uf2() {
+        let data = [
+            uf2_block(0x0001_FFF0, &(0..16).collect::<Vec<u8>>(), 0),
+            uf2_block(0x0002_0000, &(16..32).collect::<Vec<u8>>(), 0x2000),
+            uf2_block(0x1000_0000, b"not for flash", 0x0000_0001),
+            uf2_block(0x0003_0000, &[0xAA, 0xBB, 0xCC], 0),
+        ]
+        .concat();
+        assert_eq!(Format::detect(&data), Format::Uf2);
+        assert_eq!(merge(uf2::segments(&data).unwrap()).unwrap(), expected());
+        assert!(uf2::segments(&data[..600]).is_err());
+    }
+
+    #[test]
+    fn vector_tables() {
+        // Stack at the end of RAM, reset handler right after the table
+        let mut data = [0x2004_0000u32, 0x2000_0101].map(u32::to_le_bytes).concat();
+        data.resize(0x104, 0);
+        let segments = [Segment { addr: 0x2000_0000, data }];
+        assert_eq!(check_vector_table(&segments, 0x2000_0000), Ok(()));
+
+        // `.data` laid out first, and the table after it
+        assert!(check_vector_table(&segments, 0x2000_0080).unwrap_err().contains("stack pointer"));
+        assert!(check_vector_table(&segments, 0x2000_0004).unwrap_err().contains("aligned"));
+        assert!(check_vector_table(&segments, 0x2000_0200).unwrap_err().contains("nothing is loaded"));
+
+        let mut data = [0x2004_0000u32, 0x0000_8101].map(u32::to_le_bytes).concat();
+        data.resize(0x104, 0);
+        let segments = [Segment { addr: 0x2000_0000, data }];
+        assert!(check_vector_table(&segments, 0x2000_0000).unwrap_err().contains("reset vector"));
+    }
+
+    #[test]
+    fn relocate() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/moveable.elf");
+        let scratch = MemRange { start: 0x2000_0000, len: 0x1_0000 };
+        let word = |segments: &[Segment], addr: u32| {
+            let seg = &segments[0];
+            let offset = (addr - seg.addr) as usize;
+            u32::from_le_bytes(seg.data[offset..][..4].try_into().unwrap())
+        };
+
+        let (segments, vector_table) = relocated(path, 0x2000_8000, scratch).unwrap();
+        assert_eq!(vector_table, Some(0x2000_8000));
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].addr, 0x2000_8000);
+        // The stack is absolute, the reset vector and the literal pool
+        // (ABS32 and RELATIVE) and the `.data` pointer (ABS32) move
+        assert_eq!(word(&segments, 0x2000_8000), 0x2004_0000);
+        assert_eq!(word(&segments, 0x2000_8004), 0x2000_805D);
+        assert_eq!(word(&segments, 0x2000_8070), 0x2000_80F0);
+        assert_eq!(word(&segments, 0x2000_8074), 0x2000_806D);
+
+        // `.bss` has to fit too
+        assert!(relocated(path, 0x2000_FF10, scratch).unwrap_err().to_string().contains("don't fit"));
+        assert!(relocated(path, 0x2000_FF0C, scratch).is_ok());
+        let elsewhere = MemRange { start: 0x2000_8000, len: 0x8000 };
+        assert!(relocated(path, 0x2000_8000, elsewhere).unwrap_err().to_string().contains("isn't in scratch"));
+    }
+
+    #[test]
+    fn pages() {
+        let segments = [
+            Segment { addr: 0x8FF0, data: vec![0x11; 0x20] },
+            Segment { addr: 0xA000, data: vec![0x22; 4] },
+            Segment { addr: 0x2_0004, data: vec![0x33; 4] },
+        ];
+        let pages = flash_pages(&segments, 0x1000, 0x2000);
+
+        // 0x8000 and 0x9000 fill up the first upload, 0xA000 starts another
+        let addrs: Vec<_> = pages.iter().map(|p| (p.addr, p.data.len())).collect();
+        assert_eq!(addrs, [(0x8000, 0x2000), (0xA000, 0x1000), (0x2_0000, 0x1000)]);
+        assert_eq!(pages[0].data[0xFEF..0x1011], [[0xFF].as_slice(), &[0x11; 0x20], &[0xFF]].concat());
+        assert_eq!(pages[2].data[..12], [0xFF, 0xFF, 0xFF, 0xFF, 0x33, 0x33, 0x33, 0x33, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn merge_overlaps() {
+        let segs = vec![
+            Segment { addr: 0x100, data: vec![1; 16] },
+            Segment { addr: 0x108, data: vec![2; 16] },
+        ];
+        assert!(merge(segs).is_err());
+        assert_eq!(Format::detect(&[0x00, 0x20, 0x00, 0x20]), Format::Bin);
+    }
+}
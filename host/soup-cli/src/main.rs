@@ -5,7 +5,10 @@ use postcard::{
 };
 use serialport::SerialPort;
 use soup_icd::{FromSoup, Managed, ToSoup};
-use stage0_icd::{Error as IcdError, PeekBytes, Poked, Request, Response as S0Response};
+use sha2::Digest;
+use stage0_icd::{
+    DeviceInfo, PeekBytes, RebootTarget, Request, Response as S0Response, Slot, Slots, Trial, Version,
+};
 use std::{
     cmp::min,
     error::Error,
@@ -13,25 +16,52 @@ use std::{
     io::{ErrorKind, Read, Write},
     ops::DerefMut,
     sync::mpsc::channel,
+    time::Instant,
 };
 
 mod cli;
 mod elf;
+mod image;
+mod load;
 mod port;
+mod schema;
+#[cfg(unix)]
+mod simulate;
+mod uf2;
+mod upload;
 
 use crate::{
-    cli::{Peek, Poke, Run, Soup, Stage0, WriteBytes},
-    elf::parse_loadable,
-    port::{connect, PortKind},
+    cli::{Bench, Call, Image, Peek, Poke, Reboot, Run, Soup, SoupCli, Stage0, Verify},
+    elf::function_addr,
+    image::{is_image, read_image, Packed},
+    load::{parse_loadable, Loadable, Segment},
+    port::{connect_app, connect_stage0, Stage0Port},
+    upload::{split, upload, upload_acked, upload_windowed, WINDOWED},
 };
 
+/// The first stage0 protocol with the `Crc32`/`Sha256` requests
+const CHECKSUMS: Version = Version { major: 3, minor: 1, patch: 0 };
+
+/// The first stage0 protocol with `BootloadImage`/`FlashCopyImage`
+const IMAGES: Version = Version { major: 5, minor: 2, patch: 0 };
+
+/// The first stage0 protocol with signed images
+const SIGNED_IMAGES: Version = Version { major: 5, minor: 3, patch: 0 };
+
+/// The first stage0 protocol with A/B slots
+const SLOTS: Version = Version { major: 5, minor: 4, patch: 0 };
+
+/// The first stage0 protocol with `Call`
+const CALLS: Version = Version { major: 5, minor: 7, patch: 0 };
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let cmd = Soup::parse();
+    let SoupCli { port: path, cmd } = SoupCli::parse();
+    let path = path.as_deref();
 
     match cmd {
         Soup::Reboot => {
             println!("Sending reboot command.");
-            let mut port = connect(PortKind::SoupApp)?;
+            let mut port = connect_app(path)?;
             send(ToSoup::Control(soup_icd::Control::Reboot), port.deref_mut())
         }
         Soup::Nop => {
@@ -39,91 +69,352 @@ fn main() -> Result<(), Box<dyn Error>> {
             Ok(())
         }
         Soup::Stage0(shim) => {
-            let mut port = connect(PortKind::Stage0)?;
+            let mut port = connect_stage0(path)?;
             match shim.shim {
-                Stage0::Peek(cmd) => peek(cmd, port.deref_mut()),
-                Stage0::Poke(cmd) => poke(cmd, port.deref_mut()).map(drop),
-                Stage0::Bootload(cmd) => {
-                    send(
-                        Request::Bootload {
-                            addr: cmd.address.0,
-                        },
-                        port.deref_mut(),
-                    )?;
-                    println!("Sent bootload command.");
-                    Ok(())
-                }
-                Stage0::FlashPeek(cmd) => flash_peek(cmd, port.deref_mut()),
-                Stage0::FlashPoke(cmd) => flash_poke(cmd, port.deref_mut()),
+                Stage0::Peek(cmd) => peek(cmd, &mut port),
+                Stage0::Poke(cmd) => poke(cmd, &mut port).map(drop),
+                Stage0::Bootload(cmd) => bootload(cmd.address.0, &mut port),
+                Stage0::Reboot(cmd) => reboot(cmd, &mut port),
+                Stage0::ClearMagic => clear_magic(&mut port),
+                Stage0::FlashPeek(cmd) => flash_peek(cmd, &mut port),
+                Stage0::FlashPoke(cmd) => flash_poke(cmd, &mut port),
+                Stage0::Info => info(&mut port),
+                Stage0::Verify(cmd) => verify(cmd, &mut port),
+                Stage0::Bench(cmd) => bench(cmd, &mut port),
+                Stage0::Slots => slots(&mut port),
+                Stage0::Call(cmd) => call(cmd, &mut port),
             }
         }
         Soup::Stdio => {
-            let mut port = connect(PortKind::SoupApp)?;
+            let mut port = connect_app(path)?;
             stdio(port.deref_mut())
         }
-        Soup::Run(Run { elf_path }) => run(elf_path),
+        Soup::Run(cmd) => run(cmd, path),
+        Soup::Keygen(cmd) => image::keygen(cmd),
+        Soup::Sign(cmd) => image::sign(cmd),
+        Soup::Uf2(cmd) => uf2::uf2(cmd),
+        Soup::Schema => schema::schema(),
+        #[cfg(unix)]
+        Soup::Simulate(cmd) => simulate::simulate(cmd),
+        Soup::Image(shim) => match shim.cmd {
+            Image::Pack(cmd) => image::pack(cmd),
+            Image::Show(cmd) => image::show(&cmd.path),
+            Image::Flash(cmd) => {
+                let mut port = connect_stage0(path)?;
+                flash_image(&cmd.path, &mut port)
+            }
+            Image::Update(cmd) => {
+                let mut port = connect_stage0(path)?;
+                update(&cmd.path, &mut port)
+            }
+        },
     }?;
 
     Ok(())
 }
 
-fn run(path: String) -> Result<(), Box<dyn Error>> {
-    let load = parse_loadable(path)?;
-    let mut port = connect(PortKind::Stage0)?;
+fn run(cmd: Run, path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let elf_path = cmd.elf_path;
+    if is_image(&elf_path)? {
+        if cmd.base.is_some() || cmd.at.is_some() || cmd.no_boot {
+            return Err(format!(
+                "{elf_path} is a packed image, which says where it goes and is checked as it boots, so it takes no --base, --at or --no-boot"
+            )
+            .into());
+        }
+        return run_image(&elf_path, path);
+    }
+
+    let mut port = connect_stage0(path)?;
+    let (segments, vector_table) = match cmd.at {
+        Some(at) => load::relocated(&elf_path, at.0, get_info(&mut port)?.scratch)?,
+        None => {
+            let segments = load::segments(&elf_path, cmd.base.map(|b| b.0))?;
+            let vector_table = load::vector_table(&elf_path, &segments)?;
+            (segments, vector_table)
+        }
+    };
 
-    // Poke elf file into memory
-    poke(
-        Poke {
-            address: cli::Address(load.addr),
-            val: Some(WriteBytes(load.data)),
-            file: None,
-        },
-        port.deref_mut(),
-    )?;
+    // Put the program where it goes, and make sure it all arrived intact
+    // before we jump into it. Files that don't say where their vector table
+    // is are taken to start with it, like cortex-m-rt lays them out: in RAM
+    // if anything goes there, in flash otherwise.
+    let lowest_ram = write_segments(&mut port, &segments)?;
+    let addr = vector_table
+        .or(lowest_ram)
+        .or(segments.first().map(|seg| seg.addr))
+        .ok_or_else(|| format!("{elf_path} has nothing to run"))?;
+    if let Err(e) = load::check_vector_table(&segments, addr) {
+        println!(" -> Warning: {e}{}", if cmd.no_boot { "" } else { ", booting it anyway" });
+    }
+
+    if cmd.no_boot {
+        println!(" -> Loaded, boot it with `soup-cli stage0 bootload -a 0x{addr:08X}`");
+        return Ok(());
+    }
 
     // Bootload
-    send(Request::Bootload { addr: load.addr }, port.deref_mut())?;
-    println!("Sent bootload command.");
+    bootload(addr, &mut port)?;
+
+    // Drop the port, reconnect as an app, attach to stdio
+    drop(port);
+
+    let mut port = connect_app(path)?;
+    stdio(port.deref_mut())?;
+
+    Ok(())
+}
+
+/// Like `run`, but stage0 checks the image against its header before booting it
+fn run_image(image_path: &str, path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let packed = read_image(image_path)?;
+    let header = packed.header;
+    let mut port = connect_stage0(path)?;
+    require_images(&port, &packed)?;
+
+    println!(" -> Sending {} byte image, version {}", packed.image.len(), header.app_version);
+    upload(&mut port, header.load_addr as usize, &packed.image)?;
+
+    let req = match packed.signature {
+        Some(signature) => Request::BootloadSignedImage { header, signature },
+        None => Request::BootloadImage { header },
+    };
+    port.request(req, |r| match r {
+        S0Response::Bootloading { addr } if *addr == header.entry => Some(()),
+        _ => None,
+    })?;
+    println!("Bootloading 0x{:08X}.", header.entry);
 
     // Drop the port, reconnect as an app, attach to stdio
     drop(port);
 
-    let mut port = connect(PortKind::SoupApp)?;
+    let mut port = connect_app(path)?;
     stdio(port.deref_mut())?;
 
     Ok(())
 }
 
-fn flash_poke(cmd: Poke, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
-    // First, poke the data into RAM, but start at the origin.
-    let mut ram_poke = cmd.clone();
-    ram_poke.address = cli::Address(0x2000_0000);
-    println!(" -> Sending to RAM...");
-    let len = poke(ram_poke, port)?;
-
-    // Then, send a ram copy command
-    let copy_cmd = Request::FlashCopy {
-        ram_start: 0x2000_0000,
-        flash_start: cmd.address.0 as usize,
-        len,
+fn flash_image(path: &str, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let packed = read_image(path)?;
+    let (header, data) = (packed.header, &packed.image);
+    require_images(port, &packed)?;
+
+    let info = get_info(port)?;
+    if data.len() > info.scratch.len {
+        return Err(format!(
+            "{} bytes doesn't fit in the {} byte scratch area",
+            data.len(),
+            info.scratch.len
+        )
+        .into());
+    }
+
+    println!(" -> Sending {} byte image to RAM...", data.len());
+    upload(port, info.scratch.start, data)?;
+
+    println!(" -> Sending RAM->Flash copy command");
+    let ram_start = info.scratch.start;
+    let req = match packed.signature {
+        Some(signature) => Request::FlashCopySignedImage { ram_start, header, signature },
+        None => Request::FlashCopyImage { ram_start, header },
     };
-    send(copy_cmd, port)?;
+    port.request(req, |r| match r {
+        S0Response::FlashCopied => Some(()),
+        _ => None,
+    })?;
 
-    println!(" -> Sent RAM->Flash copy command");
+    println!(" -> Image version {} is in flash at 0x{:08X}!", header.app_version, header.load_addr);
 
-    let _ = recv_s0::<_, ()>(
-        |r| match r {
-            S0Response::FlashCopied => Some(()),
-            _ => None,
-        },
-        port,
-    )?;
+    Ok(())
+}
 
+/// Flash an image into the inactive slot, and boot it on trial. If it doesn't
+/// call `soup_stuff::confirm_boot` before the next reset, stage0 goes back to
+/// the image in the active slot.
+fn update(path: &str, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let packed = read_image(path)?;
+    let header = packed.header;
+    let Slots { layout, state } = get_slots(port)?;
+
+    let slot = state.active.other();
+    let slot_addr = layout.slot_addr(slot);
+    if header.load_addr != slot_addr {
+        return Err(format!(
+            "{path} is linked for 0x{:08X}, but the inactive slot {slot:?} starts at 0x{slot_addr:08X}",
+            header.load_addr
+        )
+        .into());
+    }
+    if header.image_len > layout.slot_size {
+        return Err(format!(
+            "{} bytes doesn't fit in the {} byte slot",
+            header.image_len, layout.slot_size
+        )
+        .into());
+    }
+
+    flash_image(path, port)?;
+
+    port.request(Request::TrialBoot { slot }, |r| match r {
+        S0Response::TrialBooting { slot: s } if *s == slot => Some(()),
+        _ => None,
+    })?;
+    println!("Booting slot {slot:?} on trial, it has to confirm itself before the next reset.");
+
+    Ok(())
+}
+
+fn get_slots(port: &mut Stage0Port) -> Result<Slots, Box<dyn Error>> {
+    if !port.supports(SLOTS) {
+        return Err(format!("stage0 v{} has no A/B slots, v{SLOTS} or newer is needed", port.version).into());
+    }
+    port.request(Request::GetSlots, |r| match r {
+        S0Response::Slots(slots) => Some(*slots),
+        _ => None,
+    })
+}
+
+fn slots(port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let Slots { layout, state } = get_slots(port)?;
+
+    for slot in [Slot::A, Slot::B] {
+        let start = layout.slot_addr(slot);
+        let role = match state.trial {
+            _ if slot == state.active => "active",
+            Some(Trial { slot: s, booted: false }) if s == slot => "on trial, boots next",
+            Some(Trial { slot: s, booted: true }) if s == slot => "on trial, booted but not confirmed",
+            _ => "inactive",
+        };
+        println!("Slot {slot:?}: 0x{start:08X}..0x{:08X}, {role}", start + layout.slot_size);
+    }
+
+    let next = state.boot().1;
+    println!(" -> Slot {next:?} boots next.");
+
+    Ok(())
+}
+
+fn require_images(port: &Stage0Port, packed: &Packed) -> Result<(), Box<dyn Error>> {
+    let (needed, what) = match packed.signature {
+        Some(_) => (SIGNED_IMAGES, "signed images"),
+        None => (IMAGES, "image headers"),
+    };
+    if port.supports(needed) {
+        Ok(())
+    } else {
+        Err(format!("stage0 v{} can't check {what}, v{needed} or newer is needed", port.version).into())
+    }
+}
+
+/// Write every segment where it goes: poke the ones in scratch RAM, and copy
+/// the ones in flash there through scratch RAM. All of them are checked
+/// before anything is written.
+///
+/// Returns where the segments in RAM start, if there are any.
+fn write_segments(port: &mut Stage0Port, segments: &[Segment]) -> Result<Option<u32>, Box<dyn Error>> {
+    let info = get_info(port)?;
+    let (mut ram, mut flash) = (vec![], vec![]);
+
+    for seg in segments {
+        let (addr, len) = (seg.addr as usize, seg.data.len());
+        if info.scratch.contains(addr, len) {
+            ram.push(seg);
+        } else if addr >= info.bootloader.end() && addr.saturating_add(len) <= info.flash_size {
+            flash.push(seg.clone());
+        } else {
+            return Err(format!(
+                "0x{addr:08X}..0x{:08X} is neither in scratch RAM (0x{:08X}..0x{:08X}) nor in flash above stage0 (0x{:08X}..0x{:08X})",
+                addr + len,
+                info.scratch.start,
+                info.scratch.end(),
+                info.bootloader.end(),
+                info.flash_size,
+            )
+            .into());
+        }
+    }
+
+    // Flash goes first, it passes through the scratch RAM the rest goes to
+    write_flash(port, &info, &flash)?;
+
+    for seg in &ram {
+        println!(" -> Loading {} bytes to 0x{:08X}", seg.data.len(), seg.addr);
+        upload(port, seg.addr as usize, &seg.data)?;
+        auto_verify(port, seg.addr as usize, &seg.data, false)?;
+    }
+
+    Ok(ram.iter().map(|seg| seg.addr).min())
+}
+
+fn flash_poke(cmd: Poke, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let info = get_info(port)?;
+    // Gaps between segments are left erased
+    let Loadable { addr, data } = poke_data(&cmd, 0xFF)?;
+    let flash_start = addr as usize;
+    let len = data.len();
+
+    // Check everything we can before spending time on the upload
+    if (flash_start & (info.flash_page_size - 1)) != 0 {
+        return Err(format!(
+            "Flash address 0x{flash_start:08X} is not aligned to the {} byte page size",
+            info.flash_page_size
+        )
+        .into());
+    }
+    if flash_start < info.bootloader.end() {
+        return Err(format!(
+            "Flash address 0x{flash_start:08X} would overwrite the bootloader (0x{:08X}..0x{:08X})",
+            info.bootloader.start,
+            info.bootloader.end(),
+        )
+        .into());
+    }
+    if flash_start.saturating_add(len) > info.flash_size {
+        return Err(format!(
+            "{len} bytes at 0x{flash_start:08X} doesn't fit in {} bytes of flash",
+            info.flash_size
+        )
+        .into());
+    }
+
+    write_flash(port, &info, &[Segment { addr, data }])?;
     println!(" -> Completed!");
 
     Ok(())
 }
 
+/// Flash `segments` through the scratch RAM, as many whole pages at a time
+/// as fit. Windows flash already holds are skipped, so after an error, the
+/// same command picks up where it stopped.
+fn write_flash(port: &mut Stage0Port, info: &DeviceInfo, segments: &[Segment]) -> Result<(), Box<dyn Error>> {
+    for window in load::flash_pages(segments, info.flash_page_size, info.scratch.len) {
+        let (flash_start, len) = (window.addr as usize, window.data.len());
+        if port.supports(CHECKSUMS) && region_matches(port, flash_start, &window.data, true, false)? {
+            println!(" -> 0x{flash_start:08X}..0x{:08X} is already flashed", flash_start + len);
+            continue;
+        }
+
+        println!(" -> Flashing {len} bytes at 0x{flash_start:08X}");
+        flash_window(port, info.scratch.start, &window).map_err(|e| {
+            format!("{e}\nEverything below 0x{flash_start:08X} is flashed, run the same command again to carry on from there")
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Stage one window in scratch RAM, and copy it to flash
+fn flash_window(port: &mut Stage0Port, ram_start: usize, window: &Segment) -> Result<(), Box<dyn Error>> {
+    let (flash_start, len) = (window.addr as usize, window.data.len());
+    upload(port, ram_start, &window.data)?;
+    let copy_cmd = Request::FlashCopy { ram_start, flash_start, len };
+    port.request(copy_cmd, |r| match r {
+        S0Response::FlashCopied => Some(()),
+        _ => None,
+    })?;
+    auto_verify(port, flash_start, &window.data, true)
+}
+
 fn stdio(port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
     println!("====================");
     println!("Forwarding Stdio... ");
@@ -143,7 +434,7 @@ fn stdio(port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
         loop {
             match stdin.read(&mut buf) {
                 Ok(n) => {
-                    tx.send((&buf[..n]).to_vec()).unwrap();
+                    tx.send(buf[..n].to_vec()).unwrap();
                 }
                 Err(_) => todo!(),
             }
@@ -181,6 +472,8 @@ fn stdio(port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
                             eprint!("{}", String::from_utf8_lossy(r.as_slice()));
                             stderr.flush()?;
                         }
+                        // A late answer to our hello
+                        FromSoup::Hello(_) => {}
                         FromSoup::ControlResponse(_r) => todo!(),
                         FromSoup::FromApp(_r) => todo!(),
                         FromSoup::Error(_r) => todo!(),
@@ -204,49 +497,223 @@ fn send<T: serde::Serialize>(cmd: T, port: &mut dyn SerialPort) -> Result<(), Bo
     Ok(())
 }
 
-fn recv_s0<F, T>(matcher: F, port: &mut dyn SerialPort) -> Result<T, Box<dyn Error>>
-where
-    F: Fn(&S0Response<'_>) -> Option<T>,
-{
-    let mut acc = CobsAccumulator::<512>::new();
+fn bootload(addr: u32, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    port.request(Request::Bootload { addr }, |r| match r {
+        S0Response::Bootloading { addr: a } if *a == addr => Some(()),
+        _ => None,
+    })?;
+    println!("Bootloading 0x{addr:08X}.");
+    Ok(())
+}
 
-    let mut raw_buf = [0u8; 32];
+fn reboot(cmd: Reboot, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let target = match (cmd.ram, cmd.flash) {
+        (true, _) => RebootTarget::Ram,
+        (false, Some(addr)) => RebootTarget::Flash { addr: addr.0 },
+        (false, None) => RebootTarget::Stage0,
+    };
+    port.request(Request::Reboot { target }, |r| match r {
+        S0Response::Rebooting(t) if *t == target => Some(()),
+        _ => None,
+    })?;
+    println!("Rebooting to {target:?}.");
+    Ok(())
+}
 
-    'outer: loop {
-        let mut buf = match port.read(&mut raw_buf) {
-            Ok(0) => todo!(),
-            Ok(n) => &raw_buf[..n],
-            Err(e) if e.kind() == ErrorKind::TimedOut => {
-                continue 'outer;
-            }
-            Err(e) => {
-                panic!("{e:?}");
-            }
+fn call(cmd: Call, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    if !port.supports(CALLS) {
+        return Err(format!("stage0 v{} can't call functions, v{CALLS} or newer is needed", port.version).into());
+    }
+    let addr = function_addr(&cmd.elf_path, &cmd.symbol)?;
+
+    if !cmd.no_load {
+        write_segments(port, &load::segments(&cmd.elf_path, None)?)?;
+    }
+
+    let mut args = [0u32; 4];
+    args.iter_mut().zip(&cmd.args).for_each(|(a, arg)| *a = arg.0);
+
+    println!(" -> Calling {} at 0x{addr:08X}", cmd.symbol);
+    let r0 = port.request(Request::Call { addr, args }, |r| match r {
+        S0Response::Called { r0 } => Some(*r0),
+        _ => None,
+    })?;
+    println!("{} returned 0x{r0:08X} ({r0})", cmd.symbol);
+
+    Ok(())
+}
+
+fn clear_magic(port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    port.request(Request::ClearMagic, |r| match r {
+        S0Response::MagicCleared => Some(()),
+        _ => None,
+    })?;
+    println!("Magic cleared.");
+    Ok(())
+}
+
+fn get_info(port: &mut Stage0Port) -> Result<DeviceInfo, Box<dyn Error>> {
+    port.request(Request::GetInfo, |r| match r {
+        S0Response::Info(info) => Some(info.clone()),
+        _ => None,
+    })
+}
+
+fn info(port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let info = get_info(port)?;
+    let range = |r: &stage0_icd::MemRange| format!("0x{:08X}..0x{:08X} ({} bytes)", r.start, r.end(), r.len);
+
+    println!("stage0 version:  {}", info.bootloader_version);
+    println!("ICD version:     {}", info.icd_version);
+    println!("Device ID:       {:016X}", info.device_id);
+    println!("Scratch RAM:     {}", range(&info.scratch));
+    println!("Magic RAM:       {}", range(&info.magic));
+    println!("Flash size:      {} bytes", info.flash_size);
+    println!("Flash page size: {} bytes", info.flash_page_size);
+    println!("Bootloader:      {}", range(&info.bootloader));
+
+    Ok(())
+}
+
+fn bench(cmd: Bench, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    let info = get_info(port)?;
+    let len = cmd.size.unwrap_or(info.scratch.len);
+    if len > info.scratch.len {
+        return Err(format!(
+            "{len} bytes doesn't fit in the {} byte scratch area",
+            info.scratch.len
+        )
+        .into());
+    }
+
+    // Something less compressible than all zeroes, in case the link cares.
+    // Sent uncompressed either way, this measures the link.
+    let data: Vec<u8> = (0..len).map(|i| (i ^ (i >> 8)) as u8).collect();
+
+    let windowed = !cmd.acked && port.supports(WINDOWED);
+    println!(
+        " -> Uploading {len} bytes to 0x{:08X}, {}",
+        info.scratch.start,
+        if windowed { "windowed" } else { "one chunk at a time" },
+    );
+
+    let start = Instant::now();
+    if windowed {
+        upload_windowed(port, info.scratch.start, &data, &split(&data))?;
+    } else {
+        upload_acked(port, info.scratch.start, &data)?;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    auto_verify(port, info.scratch.start, &data, false)?;
+    println!(
+        " -> {len} bytes in {elapsed:.3}s: {:.0} bytes/s ({:.1} KiB/s)",
+        len as f64 / elapsed,
+        len as f64 / elapsed / 1024.0,
+    );
+
+    Ok(())
+}
+
+fn verify(cmd: Verify, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
+    // Only what's in the file, whatever is in the gaps between its segments
+    // doesn't matter
+    let segments = load::segments(&cmd.file, cmd.address.map(|a| a.0))?;
+    let info = get_info(port)?;
+
+    for segment in segments {
+        let (addr, data) = (segment.addr as usize, segment.data);
+
+        // Figure out whether this is a RAM or Flash range
+        let flash = if info.scratch.contains(addr, data.len()) {
+            false
+        } else if addr.saturating_add(data.len()) <= info.flash_size {
+            true
+        } else {
+            return Err(format!(
+                "0x{addr:08X}..0x{:08X} is neither in scratch RAM nor in flash",
+                addr + data.len()
+            )
+            .into());
         };
 
-        'cobs: while !buf.is_empty() {
-            buf = match acc.feed_ref::<Result<S0Response<'_>, IcdError>>(buf) {
-                FeedResult::Consumed => break 'cobs,
-                FeedResult::OverFull(new_wind) => new_wind,
-                FeedResult::DeserError(new_wind) => new_wind,
-                FeedResult::Success { data, .. } => {
-                    return match data {
-                        Ok(r) => {
-                            if let Some(t) = matcher(&r) {
-                                Ok(t)
-                            } else {
-                                Err(format!("Unexpected: {r:?}").into())
-                            }
-                        }
-                        Err(e) => Err(format!("Error: {e:?}").into()),
-                    }
-                }
-            };
-        }
+        verify_region(port, addr, &data, flash, cmd.sha256)?;
+        println!(" -> {} bytes at 0x{addr:08X} match!", data.len());
+    }
+
+    Ok(())
+}
+
+/// Verify an upload if stage0 is new enough to do it
+fn auto_verify(
+    port: &mut Stage0Port,
+    addr: usize,
+    data: &[u8],
+    flash: bool,
+) -> Result<(), Box<dyn Error>> {
+    if port.supports(CHECKSUMS) {
+        verify_region(port, addr, data, flash, false)?;
+        println!(" -> Verified.");
+    } else {
+        println!(" -> stage0 is too old to verify uploads, skipping.");
+    }
+    Ok(())
+}
+
+/// Have stage0 checksum `[addr, addr + data.len())`, and compare it against `data`
+fn verify_region(
+    port: &mut Stage0Port,
+    addr: usize,
+    data: &[u8],
+    flash: bool,
+    sha256: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !port.supports(CHECKSUMS) {
+        return Err(format!("stage0 v{} can't calculate checksums, v{CHECKSUMS} or newer is needed", port.version).into());
+    }
+
+    if region_matches(port, addr, data, flash, sha256)? {
+        Ok(())
+    } else {
+        Err(format!("Verify failed: {} bytes at 0x{addr:08X} don't match", data.len()).into())
     }
 }
 
-fn flash_peek(cmd: Peek, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
+/// Does stage0's checksum of `[addr, addr + data.len())` match `data`?
+fn region_matches(
+    port: &mut Stage0Port,
+    addr: usize,
+    data: &[u8],
+    flash: bool,
+    sha256: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let len = data.len();
+    let matches = if sha256 {
+        let req = match flash {
+            false => Request::Sha256 { addr, len },
+            true => Request::Sha256Flash { addr, len },
+        };
+        let remote = port.request(req, |r| match r {
+            S0Response::Sha256(s) | S0Response::Sha256Flash(s) => Some(s.digest),
+            _ => None,
+        })?;
+        remote == <[u8; 32]>::from(sha2::Sha256::digest(data))
+    } else {
+        let req = match flash {
+            false => Request::Crc32 { addr, len },
+            true => Request::Crc32Flash { addr, len },
+        };
+        let remote = port.request(req, |r| match r {
+            S0Response::Crc32(c) | S0Response::Crc32Flash(c) => Some(c.crc),
+            _ => None,
+        })?;
+        remote == crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+    };
+
+    Ok(matches)
+}
+
+fn flash_peek(cmd: Peek, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
     // Slightly less than the 64 byte packet limit
     const CHUNK_SZ: usize = 256;
 
@@ -257,19 +724,15 @@ fn flash_peek(cmd: Peek, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>
     while remain != 0 {
         let chunk = min(CHUNK_SZ, remain);
         remain -= chunk;
-        send(
+        let resp: PeekBytes<'static> = port.request(
             Request::PeekBytesFlash {
                 addr: idx,
                 len: chunk,
             },
-            port,
-        )?;
-        let resp = recv_s0::<_, PeekBytes<'static>>(
             |r| match r {
-                S0Response::PeekBytesFlash(t) if t.addr == idx => Some(t.to_owned()),
+                S0Response::PeekBytesFlash(t) => Some(t.to_owned()),
                 _ => None,
             },
-            port,
         )?;
         data.extend_from_slice(resp.val.as_slice());
         idx += chunk;
@@ -290,7 +753,7 @@ fn flash_peek(cmd: Peek, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>
     Ok(())
 }
 
-fn peek(cmd: Peek, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
+fn peek(cmd: Peek, port: &mut Stage0Port) -> Result<(), Box<dyn Error>> {
     // Slightly less than the 64 byte packet limit
     const CHUNK_SZ: usize = 256;
 
@@ -301,19 +764,15 @@ fn peek(cmd: Peek, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
     while remain != 0 {
         let chunk = min(CHUNK_SZ, remain);
         remain -= chunk;
-        send(
+        let resp: PeekBytes<'static> = port.request(
             Request::PeekBytes {
                 addr: idx,
                 len: chunk,
             },
-            port,
-        )?;
-        let resp = recv_s0::<_, PeekBytes<'static>>(
             |r| match r {
-                S0Response::PeekBytes(t) if t.addr == idx => Some(t.to_owned()),
+                S0Response::PeekBytes(t) => Some(t.to_owned()),
                 _ => None,
             },
-            port,
         )?;
         data.extend_from_slice(resp.val.as_slice());
         idx += chunk;
@@ -334,48 +793,25 @@ fn peek(cmd: Peek, port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn poke(cmd: Poke, port: &mut dyn SerialPort) -> Result<usize, Box<dyn Error>> {
-    // Slightly less than the 64 byte packet limit
-    const CHUNK_SZ: usize = 256;
-
-    let mut idx = cmd.address.0 as usize;
-
-    let data = match (cmd.val, cmd.file) {
-        (Some(val), None) => val.0,
-        (None, Some(f)) => {
-            let mut file = File::open(&f)?;
-            let mut buf = Vec::with_capacity(file.metadata()?.len().try_into()?);
-            file.read_to_end(&mut buf)?;
-            buf
+/// What a poke writes, and where. Gaps between the segments of a file are
+/// filled with `fill`.
+fn poke_data(cmd: &Poke, fill: u8) -> Result<Loadable, Box<dyn Error>> {
+    let address = cmd.address.as_ref().map(|a| a.0);
+    match (&cmd.val, &cmd.file) {
+        (Some(val), None) => {
+            let addr = address.ok_or("An address (-a) is needed for bytes")?;
+            Ok(Loadable { addr, data: val.0.clone() })
         }
-        (Some(_), Some(_)) => todo!(),
-        (None, None) => todo!(),
-    };
+        (None, Some(f)) => parse_loadable(f, address, fill),
+        _ => Err("Give either bytes (-b) or a file (-f)".into()),
+    }
+}
 
-    let mut remain = &data[..];
-    let ttl = remain.len();
-    println!("   -> len: {}", ttl);
+fn poke(cmd: Poke, port: &mut Stage0Port) -> Result<usize, Box<dyn Error>> {
+    let Loadable { addr, data } = poke_data(&cmd, 0x00)?;
+    println!("   -> len: {}", data.len());
 
-    while !remain.is_empty() {
-        let chunk_len = min(CHUNK_SZ, remain.len());
-        let (chunk, later) = remain.split_at(chunk_len);
-        remain = later;
-        send(
-            Request::PokeBytes {
-                addr: idx,
-                val: Managed::Borrowed(chunk),
-            },
-            port,
-        )?;
-        let _ = recv_s0::<_, Poked>(
-            |r| match r {
-                S0Response::Poked(t) if t.addr == idx => Some(Poked { addr: t.addr }),
-                _ => None,
-            },
-            port,
-        )?;
-        idx += chunk_len;
-    }
+    upload(port, addr as usize, &data)?;
 
-    Ok(ttl)
+    Ok(data.len())
 }
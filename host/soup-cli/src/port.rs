@@ -1,10 +1,286 @@
-use std::{time::Duration, error::Error, fmt::Display, ops::DerefMut};
+use std::{
+    error::Error,
+    fmt::Display,
+    io::ErrorKind,
+    ops::{Deref, DerefMut},
+    time::{Duration, Instant},
+};
 
+use postcard::accumulator::{CobsAccumulator, FeedResult};
 use serialport::SerialPort;
-use soup_icd::ToSoup;
+use soup_icd::{FromSoup, ToSoup};
+use stage0_icd::{FromStage0, Header, Request, Response, ToStage0};
 
+/// How long a device gets to answer our `Hello`
+const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long stage0 gets to answer a request. Erasing and writing a full
+/// scratch buffer worth of flash takes a couple of seconds.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an app we told to reboot gets before we go looking for stage0
+/// again at the same port
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+
+/// A stage0 loader that answered our `Hello` with a compatible version
+pub struct Stage0Port {
+    port: Box<dyn SerialPort>,
+    /// The protocol version stage0 reported
+    pub version: stage0_icd::Version,
+    /// Sequence number of the next request we send
+    next_seq: u16,
+    acc: CobsAccumulator<512>,
+    /// Bytes we've read from the port, but haven't fed to `acc` yet
+    pending: Vec<u8>,
+}
+
+impl Stage0Port {
+    /// Does this stage0 understand the messages added in version `since`?
+    pub fn supports(&self, since: stage0_icd::Version) -> bool {
+        (self.version.major, self.version.minor) >= (since.major, since.minor)
+    }
+
+    /// Send `req`, and wait for the response to it
+    pub fn request<F, T>(&mut self, req: Request<'_>, matcher: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(&Response<'_>) -> Option<T>,
+    {
+        let seq = self.send(req)?;
+        self.recv(seq, matcher)
+    }
+
+    /// Send `req` with the next sequence number, and return that number
+    pub fn send(&mut self, req: Request<'_>) -> Result<u16, Box<dyn Error>> {
+        let header = Header { seq: self.next_seq };
+        self.next_seq = self.next_seq.wrapping_add(1);
+        crate::send(ToStage0::Request(header, req), self.port.deref_mut())?;
+        Ok(header.seq)
+    }
+
+    /// Wait for the response to request `seq`.
+    ///
+    /// Responses to earlier requests are leftovers from a request that timed
+    /// out, and are skipped. Anything that doesn't decode is skipped until the
+    /// next frame boundary.
+    pub fn recv<F, T>(&mut self, seq: u16, matcher: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(&Response<'_>) -> Option<T>,
+    {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+
+        loop {
+            if self.pending.is_empty() {
+                if Instant::now() > deadline {
+                    return Err(format!(
+                        "Timed out waiting for the response to request #{seq}, it or its response got lost"
+                    )
+                    .into());
+                }
+
+                let mut raw_buf = [0u8; 64];
+                match self.port.read(&mut raw_buf) {
+                    Ok(0) => return Err("stage0 port closed".into()),
+                    Ok(n) => self.pending.extend_from_slice(&raw_buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            let (used, outcome) = match self.acc.feed_ref::<FromStage0<'_>>(&self.pending) {
+                FeedResult::Consumed => (self.pending.len(), None),
+                FeedResult::OverFull(rem) | FeedResult::DeserError(rem) => {
+                    (self.pending.len() - rem.len(), None)
+                }
+                FeedResult::Success { data, remaining } => {
+                    let outcome = match data {
+                        FromStage0::Response(hdr, resp) if hdr.seq == seq => Some(match resp {
+                            Ok(r) => matcher(&r).ok_or_else(|| format!("Unexpected: {r:?}").into()),
+                            Err(e) => Err(format!("Request #{seq} failed: {e}").into()),
+                        }),
+                        // Wrapping "is this sequence number before ours?"
+                        FromStage0::Response(hdr, _) if (seq.wrapping_sub(hdr.seq) as i16) > 0 => None,
+                        FromStage0::Response(hdr, _) => Some(Err(format!(
+                            "Got a response to request #{}, while waiting for #{seq}",
+                            hdr.seq
+                        )
+                        .into())),
+                        FromStage0::Hello(_) => None,
+                        // Whatever it was, if it was ours we'll time out waiting for it
+                        FromStage0::FrameError(e) => {
+                            println!("   -> stage0 couldn't read a frame: {e}");
+                            None
+                        }
+                    };
+                    (self.pending.len() - remaining.len(), outcome)
+                }
+            };
+            self.pending.drain(..used);
+
+            if let Some(outcome) = outcome {
+                return outcome;
+            }
+        }
+    }
+}
+
+impl Deref for Stage0Port {
+    type Target = dyn SerialPort;
+
+    fn deref(&self) -> &Self::Target {
+        self.port.deref()
+    }
+}
+
+impl DerefMut for Stage0Port {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.port.deref_mut()
+    }
+}
+
+/// Find a stage0 loader, and make sure it speaks a compatible protocol.
+/// Uses the port at `path` instead of looking for one, if given.
+pub fn connect_stage0(path: Option<&str>) -> Result<Stage0Port, Box<dyn Error>> {
+    let mut port = connect(PortKind::Stage0, path)?;
+
+    crate::send(ToStage0::Hello(stage0_icd::Hello::CURRENT), port.deref_mut())?;
+    let hello = recv_hello(port.deref_mut(), |frame| {
+        match postcard::from_bytes_cobs::<FromStage0<'_>>(frame) {
+            Ok(FromStage0::Hello(hello)) => Some(hello),
+            _ => None,
+        }
+    })?;
+
+    let hello = match hello {
+        Some(h) if h.protocol == stage0_icd::PROTOCOL => h,
+        // Without a USB product name to go by, the hello is the first we hear of it
+        Some(h) if h.protocol == soup_icd::PROTOCOL && path.is_some() => {
+            reboot_app(port.deref_mut())?;
+            drop(port);
+            std::thread::sleep(REBOOT_DELAY);
+            return connect_stage0(path);
+        }
+        Some(h) => {
+            return Err(format!("Device doesn't speak the stage0 protocol (0x{:08X})", h.protocol).into());
+        }
+        None => return Err(no_hello("stage0")),
+    };
+
+    let ours = stage0_icd::ICD_VERSION;
+    let theirs = hello.version;
+    check_version(
+        "stage0",
+        ours,
+        theirs,
+        ours.is_compatible(&theirs),
+        theirs.minor < ours.minor,
+    )?;
+
+    Ok(Stage0Port {
+        port,
+        version: theirs,
+        next_seq: 0,
+        acc: CobsAccumulator::new(),
+        pending: Vec::new(),
+    })
+}
+
+/// Find a soup app, and make sure it speaks a compatible protocol.
+/// Uses the port at `path` instead of looking for one, if given.
+pub fn connect_app(path: Option<&str>) -> Result<Box<dyn SerialPort>, Box<dyn Error>> {
+    let mut port = connect(PortKind::SoupApp, path)?;
+
+    crate::send(ToSoup::Hello(soup_icd::Hello::CURRENT), port.deref_mut())?;
+    let hello = recv_hello(port.deref_mut(), |frame| {
+        match postcard::from_bytes_cobs::<FromSoup<'_>>(frame) {
+            Ok(FromSoup::Hello(hello)) => Some(hello),
+            _ => None,
+        }
+    })?;
+
+    let hello = match hello {
+        Some(h) if h.protocol == soup_icd::PROTOCOL => h,
+        Some(h) if h.protocol == stage0_icd::PROTOCOL && path.is_some() => {
+            return Err(found_stage0());
+        }
+        Some(h) => {
+            return Err(format!("Device doesn't speak the soup app protocol (0x{:08X})", h.protocol).into());
+        }
+        None => return Err(no_hello("soup app")),
+    };
+
+    let ours = soup_icd::ICD_VERSION;
+    let theirs = hello.version;
+    check_version(
+        "soup app",
+        ours,
+        theirs,
+        ours.is_compatible(&theirs),
+        theirs.minor < ours.minor,
+    )?;
+
+    Ok(port)
+}
+
+fn no_hello(name: &str) -> Box<dyn Error> {
+    println!(" -> No answer to hello!");
+    println!(" -> The {name} is probably older than protocol v3.0.0, which added versioning.");
+    println!(" -> Update it, or use an older soup-cli.");
+    format!("{name} didn't answer hello").into()
+}
+
+fn check_version(
+    name: &str,
+    ours: impl Display,
+    theirs: impl Display,
+    compatible: bool,
+    older: bool,
+) -> Result<(), Box<dyn Error>> {
+    println!(" -> {name} speaks protocol v{theirs}");
+    if !compatible {
+        println!(" -> This soup-cli speaks protocol v{ours}, which is incompatible.");
+        println!(" -> Update whichever of the two is older.");
+        return Err(format!("{name} protocol v{theirs} is incompatible with v{ours}").into());
+    }
+    if older {
+        println!(" -> This soup-cli speaks protocol v{ours}, newer commands won't be available.");
+    }
+    Ok(())
+}
+
+/// Read frames one byte at a time, so we don't eat into anything that comes
+/// after the reply, until `matcher` accepts one or we give up.
+fn recv_hello<F, T>(port: &mut dyn SerialPort, mut matcher: F) -> Result<Option<T>, Box<dyn Error>>
+where
+    F: FnMut(&mut [u8]) -> Option<T>,
+{
+    let deadline = Instant::now() + HELLO_TIMEOUT;
+    let mut frame = Vec::new();
+    let mut byte = [0u8; 1];
+
+    while Instant::now() < deadline {
+        match port.read(&mut byte) {
+            Ok(0) => {}
+            Ok(_) if byte[0] == 0x00 => {
+                if let Some(t) = matcher(&mut frame) {
+                    return Ok(Some(t));
+                }
+                frame.clear();
+            }
+            Ok(_) => frame.push(byte[0]),
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(None)
+}
+
+fn connect(looking_for: PortKind, path: Option<&str>) -> Result<Box<dyn SerialPort>, Box<dyn Error>> {
+    // What's behind it is up to the hello
+    if let Some(path) = path {
+        return open(path);
+    }
 
-pub fn connect(looking_for: PortKind) -> Result<Box<dyn SerialPort>, Box<dyn Error>> {
     let mut last_err: Option<FindError> = None;
 
     let port = loop {
@@ -42,23 +318,50 @@ pub fn connect(looking_for: PortKind) -> Result<Box<dyn SerialPort>, Box<dyn Err
             (PortKind::Stage0, PortKind::Stage0) => break port,
             (PortKind::SoupApp, PortKind::SoupApp) => break port,
 
-            (PortKind::Stage0, PortKind::SoupApp) => {
-                // We found an app, looking for stage 0. Command reset.
-                println!(" -> Commanding reset to return to Stage0 Loader.");
-                crate::send(ToSoup::Control(soup_icd::Control::Reboot), port.deref_mut())?;
-            }
-            (PortKind::SoupApp, PortKind::Stage0) => {
-                println!(" -> Looking for an application, but found a stage0 loader.");
-                println!(" -> Cannot continue.");
-                println!(" -> Try Loading an app with `soup-cli stage0 ...` commands.");
-                return Err("No application found.".into());
-            }
+            (PortKind::Stage0, PortKind::SoupApp) => reboot_app(port.deref_mut())?,
+            (PortKind::SoupApp, PortKind::Stage0) => return Err(found_stage0()),
         }
     };
 
     Ok(port)
 }
 
+/// We found an app, looking for stage 0. Command reset.
+fn reboot_app(port: &mut dyn SerialPort) -> Result<(), Box<dyn Error>> {
+    println!(" -> Commanding reset to return to Stage0 Loader.");
+    crate::send(ToSoup::Control(soup_icd::Control::Reboot), port)
+}
+
+fn found_stage0() -> Box<dyn Error> {
+    println!(" -> Looking for an application, but found a stage0 loader.");
+    println!(" -> Cannot continue.");
+    println!(" -> Try Loading an app with `soup-cli stage0 ...` commands.");
+    "No application found.".into()
+}
+
+/// Open the port at `path`, waiting for it to show up if it isn't there (yet)
+fn open(path: &str) -> Result<Box<dyn SerialPort>, Box<dyn Error>> {
+    println!("Opening {path}...");
+    let mut waiting = false;
+
+    loop {
+        match serialport::new(path, 115200).timeout(Duration::from_millis(16)).open() {
+            Ok(port) => return Ok(port),
+            Err(e) if e.kind() == serialport::ErrorKind::NoDevice
+                || e.kind() == serialport::ErrorKind::Io(ErrorKind::NotFound) =>
+            {
+                if !waiting {
+                    println!(" -> {path} isn't there: {e}");
+                    println!(" -> Waiting (hit control-c to stop)...");
+                    waiting = true;
+                }
+            }
+            Err(e) => return Err(format!("Couldn't open {path}: {e}").into()),
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
 fn get_port() -> Result<(PortKind, Box<dyn SerialPort>), FindError> {
     let mut ports = vec![];
 
@@ -0,0 +1,63 @@
+//! `soup-cli schema`: the ICDs as JSON, for tooling that can't use them.
+//!
+//! The types come from `serde-reflection`, so its docs describe the format:
+//! containers by name, enum variants keyed by their tag on the wire, struct
+//! fields in the order they're sent.
+
+use std::error::Error;
+
+use serde::Serialize;
+use serde_reflection::Registry;
+
+/// How the types get on the wire
+const ENCODING: &str = "postcard, in COBS frames that each end in a zero byte. \
+    Integers wider than a byte are varints, zigzagged if signed, and USIZE is sent as U64. \
+    Enums are a varint tag followed by the variant's fields. \
+    BYTES and SEQ are a varint length followed by the items, TUPLEARRAY has no length.";
+
+#[derive(Serialize)]
+struct Schema {
+    encoding: &'static str,
+    stage0: Protocol,
+    soup: Protocol,
+}
+
+/// One side of the conversation, and the other
+#[derive(Serialize)]
+struct Protocol {
+    /// The `protocol` in its `Hello`
+    protocol: String,
+    version: String,
+    /// What the host sends
+    to_device: &'static str,
+    /// What the device answers with
+    from_device: &'static str,
+    types: Registry,
+}
+
+fn protocol(magic: u32) -> String {
+    String::from_utf8_lossy(&magic.to_le_bytes()).into_owned()
+}
+
+pub fn schema() -> Result<(), Box<dyn Error>> {
+    let schema = Schema {
+        encoding: ENCODING,
+        stage0: Protocol {
+            protocol: protocol(stage0_icd::PROTOCOL),
+            version: stage0_icd::ICD_VERSION.to_string(),
+            to_device: "ToStage0",
+            from_device: "FromStage0",
+            types: stage0_icd::schema().map_err(|e| format!("Can't trace the stage0 ICD: {e}"))?,
+        },
+        soup: Protocol {
+            protocol: protocol(soup_icd::PROTOCOL),
+            version: soup_icd::ICD_VERSION.to_string(),
+            to_device: "ToSoup",
+            from_device: "FromSoup",
+            types: soup_icd::schema().map_err(|e| format!("Can't trace the soup ICD: {e}"))?,
+        },
+    };
+
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
@@ -0,0 +1,310 @@
+//! A soup device that only exists in memory, behind a pseudo-terminal.
+//!
+//! It runs the same request handling as stage0 on the real thing, with
+//! scratch RAM, magic RAM and flash laid out like on the nRF52840. Booting an
+//! image switches it over to a simulated soup app, which prints the lines it
+//! was given and echoes stdin back, until it's told to reboot.
+
+use std::{
+    error::Error,
+    io::{ErrorKind, Read, Write},
+    mem,
+    time::{Duration, Instant},
+};
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use serialport::{SerialPort, TTYPort};
+use soup_icd::{Control, ControlResponse, FromSoup, ToSoup};
+use soup_slots::Slots;
+use stage0_core::{frame::Frames, magic, After, Board, Config, Flash, Memory, Stage0};
+use stage0_icd::{Managed, MemRange, Version};
+
+use crate::cli::Simulate;
+
+const SCRATCH: MemRange = MemRange { start: 0x2000_0000, len: 224 * 1024 };
+const MAGIC: MemRange = MemRange { start: 0x2003_FFC0, len: 64 };
+const FLASH_SIZE: usize = 1024 * 1024;
+const ACC_SIZE: usize = 512;
+
+/// Like stage0's, minus the waiting for the double tap
+const AUTOBOOT: Duration = Duration::from_millis(2000);
+
+const CONFIG: Config = Config {
+    // The stage0 firmware this one stands in for
+    version: Version { major: 2, minor: 0, patch: 0 },
+    ram: MemRange { start: 0x2000_0000, len: 256 * 1024 },
+    bootloader_size: 32 * 1024,
+    vtor_align: 256,
+    slots: soup_slots::NRF52840,
+    public_key: None,
+};
+
+type SimStage0 = Stage0<Ram, Ram, SimFlash, SimBoard>;
+
+struct Ram {
+    range: MemRange,
+    mem: Vec<u8>,
+}
+
+impl Ram {
+    fn new(range: MemRange) -> Self {
+        Self { range, mem: vec![0; range.len] }
+    }
+}
+
+impl Memory for Ram {
+    fn start(&self) -> usize {
+        self.range.start
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.mem
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+}
+
+/// Flash that behaves like the NVMC: writes only clear bits
+struct SimFlash {
+    mem: Vec<u8>,
+}
+
+impl ErrorType for SimFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for SimFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let mem = self.mem.get(offset..offset + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?;
+        bytes.copy_from_slice(mem);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl NorFlash for SimFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let mem = self.mem.get_mut(from as usize..to as usize).ok_or(NorFlashErrorKind::OutOfBounds)?;
+        mem.fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(Self::WRITE_SIZE) || !bytes.len().is_multiple_of(Self::WRITE_SIZE) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        let mem = self.mem.get_mut(offset..offset + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?;
+        mem.iter_mut().zip(bytes).for_each(|(m, b)| *m &= b);
+        Ok(())
+    }
+}
+
+impl Flash for SimFlash {
+    fn slice(&self, addr: usize, len: usize) -> &[u8] {
+        &self.mem[addr..][..len]
+    }
+}
+
+struct SimBoard;
+
+impl Board for SimBoard {
+    fn device_id(&self) -> u64 {
+        u64::from_le_bytes(*b"SIMULATE")
+    }
+
+    unsafe fn call(&mut self, addr: u32, _args: [u32; 4]) -> u32 {
+        println!(" -> Can't run the function at 0x{addr:08X} without a CPU, returning 0");
+        0
+    }
+}
+
+/// What's running on the simulated device
+enum Mode {
+    Stage0 {
+        /// When the flash application boots, unless a host shows up first
+        autoboot: Option<Instant>,
+    },
+    /// A soup app, booted by stage0
+    App,
+}
+
+struct Device {
+    stage0: SimStage0,
+    mode: Mode,
+    frames: Frames<ACC_SIZE>,
+    /// Lines the app prints after each hello
+    stdout: Vec<String>,
+}
+
+impl Device {
+    fn new(stdout: Vec<String>) -> Self {
+        let stage0 = Stage0::new(
+            Ram::new(SCRATCH),
+            Ram::new(MAGIC),
+            SimFlash { mem: vec![0xFF; FLASH_SIZE] },
+            SimBoard,
+            CONFIG,
+        );
+        let device = Self { stage0, mode: Mode::Stage0 { autoboot: None }, frames: Frames::new(), stdout };
+        device.reset()
+    }
+
+    /// What the reset handler does: boot whatever the magic says, or stay
+    /// in stage0. Memory survives, everything else starts over.
+    fn reset(self) -> Self {
+        let Stage0 { scratch, mut magic, mut flash, board, config, .. } = self.stage0;
+
+        let mode = match magic::take_boot(&mut magic) {
+            Some(addr) => {
+                println!(" -> Booting the image at 0x{addr:08X}");
+                // Like an app using `soup_stuff::confirm_boot`
+                if let Some(slot) = config.slots.slot_of(addr) {
+                    if let Ok(mut slots) = Slots::load(&mut flash, config.slots) {
+                        let _ = slots.confirm(slot);
+                    }
+                }
+                Mode::App
+            }
+            None => {
+                println!(" -> Running stage0");
+                let stay = magic::take(&mut magic, magic::STAY_IDX) == magic::STAY;
+                Mode::Stage0 { autoboot: (!stay).then(|| Instant::now() + AUTOBOOT) }
+            }
+        };
+
+        Self { stage0: Stage0::new(scratch, magic, flash, board, config), mode, frames: Frames::new(), stdout: self.stdout }
+    }
+
+    /// Handle bytes from the host, adding what goes back to `out`. Returns
+    /// whether to reset once that's sent.
+    fn feed(&mut self, bytes: &[u8], out: &mut Vec<u8>) -> After {
+        let mut outbuf = [0u8; 512];
+        let mut after = After::Nothing;
+
+        for &byte in bytes {
+            let Some((raw, broken)) = self.frames.push(byte) else {
+                continue;
+            };
+
+            // Like on the real thing, a whole frame means a host is there
+            if let (Mode::Stage0 { autoboot }, None) = (&mut self.mode, &broken) {
+                *autoboot = None;
+            }
+
+            match self.mode {
+                Mode::Stage0 { .. } => {
+                    let (resp, a) = self.stage0.handle_frame(raw, broken, &mut outbuf);
+                    out.extend_from_slice(resp);
+                    after = a;
+                }
+                Mode::App => {
+                    let msg = match broken {
+                        Some(_) => None,
+                        None => postcard::from_bytes_cobs::<ToSoup<'_>>(raw).ok(),
+                    };
+                    after = app(msg, &self.stdout, out);
+                }
+            }
+
+            // Whatever else the host sent went to the old firmware
+            if after == After::Reset {
+                break;
+            }
+        }
+
+        after
+    }
+
+    /// Boot the flash application, if it's time
+    fn autoboot(&mut self) -> After {
+        match self.mode {
+            Mode::Stage0 { autoboot: Some(at) } if Instant::now() >= at => {
+                self.mode = Mode::Stage0 { autoboot: None };
+                match self.stage0.boot_slot() {
+                    Some(addr) => {
+                        magic::arm_boot(&mut self.stage0.magic, addr);
+                        After::Reset
+                    }
+                    None => {
+                        println!(" -> No bootable flash application, staying in stage0");
+                        After::Nothing
+                    }
+                }
+            }
+            _ => After::Nothing,
+        }
+    }
+}
+
+/// The simulated soup app's answer to `msg`, which is `None` if it didn't decode
+fn app(msg: Option<ToSoup<'_>>, stdout: &[String], out: &mut Vec<u8>) -> After {
+    let mut send = |msg: FromSoup<'_>| {
+        if let Ok(bytes) = postcard::to_stdvec_cobs(&msg) {
+            out.extend(bytes);
+        }
+    };
+
+    match msg {
+        Some(ToSoup::Hello(_)) => {
+            send(FromSoup::Hello(soup_icd::Hello::CURRENT));
+            for line in stdout {
+                send(FromSoup::Stdout(Managed::from_borrowed(format!("{line}\n").as_bytes())));
+            }
+        }
+        Some(ToSoup::Stdin(data)) => send(FromSoup::Stdout(data)),
+        Some(ToSoup::Control(Control::Reboot)) => return After::Reset,
+        Some(ToSoup::Control(Control::SendAppInfo)) => {
+            let info = Managed::from_borrowed(b"soup-cli simulate");
+            send(FromSoup::ControlResponse(ControlResponse::AppInfo(info)));
+        }
+        Some(ToSoup::ToApp(data)) => send(FromSoup::FromApp(data)),
+        None => send(FromSoup::Error(soup_icd::Error::InvalidMessage)),
+    }
+
+    After::Nothing
+}
+
+pub fn simulate(cmd: Simulate) -> Result<(), Box<dyn Error>> {
+    // Holding on to our end of the device side keeps the pseudo-terminal
+    // around while no host has it open
+    let (mut host, device_side) = TTYPort::pair()?;
+    let path = device_side.name().ok_or("The pseudo-terminal has no name")?;
+
+    println!("Simulating a soup device at {path}");
+    println!(" -> Use it with `soup-cli --port {path} ...`, hit control-c to stop.");
+
+    host.set_timeout(Duration::from_millis(16))?;
+    let mut device = Device::new(cmd.stdout);
+    let mut buf = [0u8; 64];
+    let mut out = Vec::new();
+
+    loop {
+        let n = match host.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        let after = match device.feed(&buf[..n], &mut out) {
+            After::Nothing => device.autoboot(),
+            After::Reset => After::Reset,
+        };
+
+        host.write_all(&mem::take(&mut out))?;
+        if after == After::Reset {
+            device = device.reset();
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! UF2 files, as taken by USB mass storage bootloaders: 512 byte blocks,
+//! each with up to 476 bytes for one address.
+
+use std::{collections::BTreeMap, error::Error, fs, path::Path};
+
+use crate::{
+    cli::Uf2,
+    load::{self, Segment},
+};
+
+pub const BLOCK_SIZE: usize = 512;
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// The block isn't for the main flash, like a comment or an extra file
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+/// The last header word is a family ID, instead of the file size
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+
+const MAX_PAYLOAD: usize = 476;
+
+/// What we write per block. Bootloaders don't all take more, and writes at
+/// 256 byte boundaries never straddle a flash page.
+const PAYLOAD: usize = 256;
+
+/// Family IDs by name, from the UF2 spec
+pub const FAMILIES: &[(&str, u32)] = &[("nrf52840", 0xADA5_2840), ("nrf52", 0x1B57_745F)];
+
+/// A block's header, and its payload
+pub struct Block<'a> {
+    pub flags: u32,
+    pub addr: u32,
+    pub block_no: u32,
+    pub num_blocks: u32,
+    /// The family ID, or the file size without `FLAG_FAMILY_ID`
+    pub family: u32,
+    pub data: &'a [u8],
+}
+
+/// Does `data` start with a UF2 block?
+pub fn is_uf2(data: &[u8]) -> bool {
+    data.len() >= 8 && word(data, 0) == MAGIC_START0 && word(data, 1) == MAGIC_START1
+}
+
+/// Every block in `data`
+pub fn blocks(data: &[u8]) -> Result<Vec<Block<'_>>, Box<dyn Error>> {
+    if !data.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(format!("{} bytes isn't a whole number of {BLOCK_SIZE} byte UF2 blocks", data.len()).into());
+    }
+
+    data.chunks_exact(BLOCK_SIZE)
+        .enumerate()
+        .map(|(i, block)| {
+            if word(block, 0) != MAGIC_START0 || word(block, 1) != MAGIC_START1 || word(block, 127) != MAGIC_END {
+                return Err(format!("UF2 block {i} has a bad magic number").into());
+            }
+            let len = word(block, 4) as usize;
+            if len > MAX_PAYLOAD {
+                return Err(format!("UF2 block {i} claims {len} bytes, only {MAX_PAYLOAD} fit").into());
+            }
+            Ok(Block {
+                flags: word(block, 2),
+                addr: word(block, 3),
+                block_no: word(block, 5),
+                num_blocks: word(block, 6),
+                family: word(block, 7),
+                data: &block[32..][..len],
+            })
+        })
+        .collect()
+}
+
+/// The payloads of the main flash blocks in `data`, in the order they
+/// appear
+pub fn segments(data: &[u8]) -> Result<Vec<Segment>, Box<dyn Error>> {
+    Ok(blocks(data)?
+        .into_iter()
+        .filter(|block| block.flags & FLAG_NOT_MAIN_FLASH == 0)
+        .map(|block| Segment { addr: block.addr, data: block.data.to_vec() })
+        .collect())
+}
+
+/// `segments` as UF2 blocks for `family`. Every block holds the 256 bytes
+/// at a 256 byte boundary, with zeros where the segments don't cover it.
+pub fn write(segments: &[Segment], family: u32) -> Vec<u8> {
+    let mut payloads: BTreeMap<u32, [u8; PAYLOAD]> = BTreeMap::new();
+    for seg in segments {
+        for (i, byte) in seg.data.iter().enumerate() {
+            let addr = seg.addr + i as u32;
+            let offset = addr as usize % PAYLOAD;
+            payloads.entry(addr - offset as u32).or_insert([0; PAYLOAD])[offset] = *byte;
+        }
+    }
+
+    let num_blocks = payloads.len() as u32;
+    let mut out = Vec::with_capacity(payloads.len() * BLOCK_SIZE);
+    for (block_no, (addr, payload)) in payloads.iter().enumerate() {
+        let mut block = [0u8; BLOCK_SIZE];
+        let header = [
+            MAGIC_START0,
+            MAGIC_START1,
+            FLAG_FAMILY_ID,
+            *addr,
+            PAYLOAD as u32,
+            block_no as u32,
+            num_blocks,
+            family,
+        ];
+        for (i, w) in header.iter().enumerate() {
+            block[i * 4..][..4].copy_from_slice(&w.to_le_bytes());
+        }
+        block[32..][..PAYLOAD].copy_from_slice(payload);
+        block[BLOCK_SIZE - 4..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        out.extend_from_slice(&block);
+    }
+
+    out
+}
+
+/// A family ID, by name or number
+pub fn family(s: &str) -> Result<u32, String> {
+    if let Some((_, id)) = FAMILIES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+        return Ok(*id);
+    }
+    u32::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| {
+        let names: Vec<_> = FAMILIES.iter().map(|(name, _)| *name).collect();
+        format!("Unknown family {s:?}, use one of {} or a number like 0xADA52840", names.join(", "))
+    })
+}
+
+fn family_name(id: u32) -> &'static str {
+    FAMILIES.iter().find(|(_, i)| *i == id).map_or("unknown", |(name, _)| name)
+}
+
+pub fn uf2(cmd: Uf2) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(&cmd.path)?;
+    if is_uf2(&data) {
+        return dump(&cmd.path, &data);
+    }
+
+    let family = family(&cmd.family)?;
+    let segments = load::segments(&cmd.path, cmd.base.map(|b| b.0))?;
+    let uf2 = write(&segments, family);
+
+    let output = match cmd.output {
+        Some(output) => output,
+        None => Path::new(&cmd.path).with_extension("uf2").to_string_lossy().into_owned(),
+    };
+    fs::write(&output, &uf2)?;
+
+    for seg in &segments {
+        println!(" -> 0x{:08X}..0x{:08X}, {} bytes", seg.addr, seg.addr as usize + seg.data.len(), seg.data.len());
+    }
+    println!(
+        " -> Wrote {} blocks for family 0x{family:08X} ({}) to {output}",
+        uf2.len() / BLOCK_SIZE,
+        family_name(family)
+    );
+
+    Ok(())
+}
+
+/// Show what's in a UF2 file
+fn dump(path: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let blocks = blocks(data)?;
+
+    let mut families = BTreeMap::<u32, usize>::new();
+    for block in blocks.iter().filter(|b| b.flags & FLAG_FAMILY_ID != 0) {
+        *families.entry(block.family).or_default() += 1;
+    }
+    let skipped = blocks.iter().filter(|b| b.flags & FLAG_NOT_MAIN_FLASH != 0).count();
+    let misnumbered = blocks.iter().enumerate().any(|(i, b)| b.block_no as usize != i || b.num_blocks as usize != blocks.len());
+
+    println!("Blocks:   {}{}", blocks.len(), if skipped > 0 { format!(", {skipped} not for flash") } else { String::new() });
+    for (id, count) in &families {
+        println!("Family:   0x{id:08X} ({}), {count} blocks", family_name(*id));
+    }
+    if families.is_empty() {
+        println!("Family:   none");
+    }
+    if misnumbered {
+        println!(" -> The block numbers don't count up from 0 to the number of blocks!");
+    }
+
+    let segments = load::merge(segments(data)?).map_err(|e| format!("{path}: {e}"))?;
+    for seg in &segments {
+        println!("Segment:  0x{:08X}..0x{:08X}, {} bytes", seg.addr, seg.addr as usize + seg.data.len(), seg.data.len());
+    }
+
+    Ok(())
+}
+
+/// The little endian word at word offset `idx`
+fn word(data: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(data[idx * 4..][..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_blocks() {
+        // The first 4 bytes of the second block are the tail of the image
+        let segments = [Segment { addr: 0x0002_7000, data: (0..260).map(|i| i as u8).collect() }];
+        let uf2 = write(&segments, 0xADA5_2840);
+        assert_eq!(uf2.len(), 2 * BLOCK_SIZE);
+
+        let header: [u8; 32] = [
+            0x55, 0x46, 0x32, 0x0A, 0x57, 0x51, 0x5D, 0x9E, // "UF2\n", magic
+            0x00, 0x20, 0x00, 0x00, 0x00, 0x71, 0x02, 0x00, // family flag, 0x27100
+            0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // 256 bytes, block 1
+            0x02, 0x00, 0x00, 0x00, 0x40, 0x28, 0xA5, 0xAD, // of 2, nRF52840
+        ];
+        let second = &uf2[BLOCK_SIZE..];
+        assert_eq!(second[..32], header);
+        assert_eq!(second[32..36], [0x00, 0x01, 0x02, 0x03]);
+        assert!(second[36..508].iter().all(|b| *b == 0));
+        assert_eq!(second[508..], [0x30, 0x6F, 0xB1, 0x0A]);
+    }
+
+    #[test]
+    fn round_trip() {
+        // Two segments sharing a block, and one far off
+        let segments = vec![
+            Segment { addr: 0x0002_7000, data: vec![0x11; 300] },
+            Segment { addr: 0x0002_7140, data: vec![0x22; 16] },
+            Segment { addr: 0x1000_1000, data: vec![0x33; 4] },
+        ];
+        let uf2 = write(&segments, 0xADA5_2840);
+        let blocks = blocks(&uf2).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks.iter().all(|b| b.family == 0xADA5_2840 && b.num_blocks == 3));
+
+        // Back as whole blocks, with the gaps zeroed
+        let back = load::merge(super::segments(&uf2).unwrap()).unwrap();
+        let mut first = vec![0x11; 300];
+        first.resize(0x140, 0);
+        first.extend([0x22; 16]);
+        first.resize(512, 0);
+        assert_eq!(back[0], Segment { addr: 0x0002_7000, data: first });
+        assert_eq!(back[1].addr, 0x1000_1000);
+        assert_eq!(back[1].data[..5], [0x33, 0x33, 0x33, 0x33, 0x00]);
+    }
+
+    #[test]
+    fn families() {
+        assert_eq!(family("nrf52840"), Ok(0xADA5_2840));
+        assert_eq!(family("0xE48BFF56"), Ok(0xE48B_FF56));
+        assert!(family("z80").is_err());
+    }
+}
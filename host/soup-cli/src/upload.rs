@@ -0,0 +1,250 @@
+//! Getting data into stage0's scratch RAM, as fast as the link allows
+
+use std::{collections::VecDeque, error::Error};
+
+use stage0_icd::{Managed, Request, Response, Version};
+
+use crate::port::Stage0Port;
+
+/// The first stage0 protocol with `PokeBytesQuiet` and `Sync`
+pub const WINDOWED: Version = Version { major: 4, minor: 1, patch: 0 };
+
+/// The first stage0 protocol with `PokeBytesQuietLz4`
+pub const COMPRESSED: Version = Version { major: 5, minor: 5, patch: 0 };
+
+/// Bytes per `PokeBytes`, which has to fit stage0's 512 byte frame buffer.
+/// Also the most compressed bytes sent in one `PokeBytesQuietLz4`.
+const CHUNK_SZ: usize = 256;
+
+/// The most bytes one `PokeBytesQuietLz4` inflates to. Zero padding
+/// compresses well enough that a whole flash page fits in one chunk.
+const MAX_INFLATED: usize = 4096;
+
+/// Quiet pokes sent before each `Sync`
+const WINDOW_CHUNKS: usize = 16;
+
+/// How many windows may be waiting for their `Synced` at the same time.
+/// Two is enough to keep the link busy while we wait for the older one.
+const WINDOWS_IN_FLIGHT: usize = 2;
+
+/// Write `data` to `addr`, using the fastest method stage0 supports
+pub fn upload(port: &mut Stage0Port, addr: usize, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    if port.supports(COMPRESSED) {
+        let chunks = compress(data);
+        let sent: usize = chunks.iter().map(Chunk::sent).sum();
+        if sent < data.len() {
+            println!(
+                " -> Compressed {} bytes to {sent} ({:.1}%)",
+                data.len(),
+                sent as f64 * 100.0 / data.len() as f64
+            );
+        }
+        upload_windowed(port, addr, data, &chunks)
+    } else if port.supports(WINDOWED) {
+        upload_windowed(port, addr, data, &split(data))
+    } else {
+        upload_acked(port, addr, data)
+    }
+}
+
+/// Write `data` to `addr`, waiting for every chunk to be acknowledged before
+/// sending the next one
+pub fn upload_acked(port: &mut Stage0Port, addr: usize, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    for (i, chunk) in data.chunks(CHUNK_SZ).enumerate() {
+        port.request(
+            Request::PokeBytes {
+                addr: addr + i * CHUNK_SZ,
+                val: Managed::Borrowed(chunk),
+            },
+            |r| match r {
+                Response::Poked(_) => Some(()),
+                _ => None,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// One quiet poke of an upload
+pub struct Chunk {
+    /// Offset into the upload
+    offset: usize,
+    len: usize,
+    /// The chunk as an LZ4 block, if that's smaller
+    lz4: Option<Vec<u8>>,
+}
+
+impl Chunk {
+    /// How many bytes of data this chunk sends
+    fn sent(&self) -> usize {
+        self.lz4.as_ref().map_or(self.len, Vec::len)
+    }
+}
+
+/// Plain `CHUNK_SZ` chunks
+pub fn split(data: &[u8]) -> Vec<Chunk> {
+    (0..data.len())
+        .step_by(CHUNK_SZ)
+        .map(|offset| Chunk { offset, len: CHUNK_SZ.min(data.len() - offset), lz4: None })
+        .collect()
+}
+
+/// Chunks that are compressed where that helps, each inflating on its own.
+///
+/// Every chunk starts out as big as stage0 could inflate it, and is halved
+/// until its LZ4 block fits in a poke. Whatever doesn't shrink even at
+/// `CHUNK_SZ` is sent as it is.
+pub fn compress(data: &[u8]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let mut len = MAX_INFLATED.min(data.len() - offset);
+        let chunk = loop {
+            let block = lz4_flex::block::compress(&data[offset..][..len]);
+            if block.len() <= CHUNK_SZ && block.len() < len {
+                break Chunk { offset, len, lz4: Some(block) };
+            }
+            if len <= CHUNK_SZ {
+                break Chunk { offset, len, lz4: None };
+            }
+            len = (len / 2).max(CHUNK_SZ);
+        };
+
+        offset += chunk.len;
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+/// A window of quiet pokes that hasn't been synced yet
+struct Window<'a> {
+    chunks: &'a [Chunk],
+    /// Sequence number of the `Sync` closing this window
+    sync: u16,
+}
+
+impl Window<'_> {
+    /// The part of the upload this window covers
+    fn range(&self) -> (usize, usize) {
+        let start = self.chunks[0].offset;
+        let last = &self.chunks[self.chunks.len() - 1];
+        (start, last.offset + last.len)
+    }
+}
+
+/// Write `data` to `addr` as `chunks`, with several windows of
+/// unacknowledged pokes in flight.
+///
+/// stage0 only tells us how many pokes of a window arrived. Windows that lost
+/// some are sent again at the end with acknowledged, uncompressed pokes, so
+/// anything that keeps failing gets reported with a proper error.
+pub fn upload_windowed(
+    port: &mut Stage0Port,
+    addr: usize,
+    data: &[u8],
+    chunks: &[Chunk],
+) -> Result<(), Box<dyn Error>> {
+    let mut windows = chunks.chunks(WINDOW_CHUNKS);
+    let mut in_flight = VecDeque::new();
+    let mut failed = Vec::new();
+
+    loop {
+        // Keep the pipe full
+        while in_flight.len() < WINDOWS_IN_FLIGHT {
+            let Some(window) = windows.next() else {
+                break;
+            };
+            for chunk in window {
+                let addr = addr + chunk.offset;
+                port.send(match &chunk.lz4 {
+                    Some(block) => Request::PokeBytesQuietLz4 { addr, len: chunk.len, val: Managed::Borrowed(block) },
+                    None => Request::PokeBytesQuiet { addr, val: Managed::Borrowed(&data[chunk.offset..][..chunk.len]) },
+                })?;
+            }
+            let sync = port.send(Request::Sync)?;
+            in_flight.push_back(Window { chunks: window, sync });
+        }
+
+        let Some(window) = in_flight.pop_front() else {
+            break;
+        };
+        let pokes = port.recv(window.sync, |r| match r {
+            Response::Synced(s) => Some(s.pokes as usize),
+            _ => None,
+        })?;
+
+        let expected = window.chunks.len();
+        if pokes != expected {
+            println!(
+                "   -> {} of {expected} chunks at 0x{:08X} got lost, resending",
+                expected.saturating_sub(pokes),
+                addr + window.range().0,
+            );
+            failed.push(window.range());
+        }
+    }
+
+    // Only now, so the `Synced`s of later windows don't arrive in the middle
+    for (start, end) in failed {
+        upload_acked(port, addr + start, &data[start..end])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn inflate(data: &[u8], chunks: &[Chunk]) -> Vec<u8> {
+        let mut out = vec![0xAA; data.len()];
+        for chunk in chunks {
+            let dest = &mut out[chunk.offset..][..chunk.len];
+            match &chunk.lz4 {
+                Some(block) => {
+                    assert!(block.len() <= CHUNK_SZ);
+                    assert_eq!(lz4_flex::block::decompress_into(block, dest).unwrap(), chunk.len);
+                }
+                None => dest.copy_from_slice(&data[chunk.offset..][..chunk.len]),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        // Code-ish noise, then padding, then a table, like a real image
+        let mut state = 0x1234_5678u32;
+        let mut data: Vec<u8> = (0..3000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        data.extend([0; 20_000]);
+        data.extend((0..5000).map(|i| (i % 7) as u8));
+
+        let chunks = compress(&data);
+        assert_eq!(inflate(&data, &chunks), data);
+
+        let sent: usize = chunks.iter().map(Chunk::sent).sum();
+        assert!(sent < data.len() / 4, "sent {sent} of {}", data.len());
+
+        // The noise can't be compressed, and goes out as it is
+        assert!(chunks[0].lz4.is_none());
+        assert_eq!(chunks[0].len, CHUNK_SZ);
+    }
+
+    #[test]
+    fn split_covers_everything() {
+        let data = vec![0x55; CHUNK_SZ * 3 + 10];
+        let chunks = split(&data);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[3].len, 10);
+        assert_eq!(inflate(&data, &chunks), data);
+    }
+}
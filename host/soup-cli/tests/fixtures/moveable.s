@@ -0,0 +1,37 @@
+@ A tiny app that runs from RAM, for testing `run --at`. The vector table
+@ and `.data` hold absolute addresses, built with:
+@
+@   llvm-mc -triple thumbv7em-none-eabi -filetype=obj moveable.s -o moveable.o
+@   rust-lld -flavor gnu -T moveable.ld --emit-relocs -pie -z notext \
+@       --no-dynamic-linker --nmagic moveable.o -o moveable.elf
+
+    .syntax unified
+    .thumb
+
+    .section .vector_table, "a"
+    .word _stack_start
+    .word reset
+
+    .text
+    .thumb_func
+    .global reset
+reset:
+    ldr r0, =counter
+    ldr r1, [r0]
+    adds r1, #1
+    str r1, [r0]
+    bl tick
+    b reset
+
+    .thumb_func
+tick:
+    bx lr
+    .ltorg
+
+    .data
+handler:
+    .word tick
+
+    .bss
+counter:
+    .word 0
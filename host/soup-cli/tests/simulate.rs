@@ -0,0 +1,240 @@
+//! End-to-end tests of soup-cli against `soup-cli simulate`
+
+#![cfg(unix)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::mpsc::{channel, Receiver},
+    time::Duration,
+};
+
+use soup_image::{AppVersion, ImageHeader};
+
+const SOUP: &str = env!("CARGO_BIN_EXE_soup-cli");
+
+/// A running `soup-cli simulate`, killed when dropped
+struct Sim {
+    child: Child,
+    path: String,
+}
+
+impl Sim {
+    fn start(stdout: &[&str]) -> Self {
+        let mut cmd = Command::new(SOUP);
+        cmd.arg("simulate").stdout(Stdio::piped());
+        stdout.iter().for_each(|line| {
+            cmd.args(["--stdout", line]);
+        });
+        let mut child = cmd.spawn().unwrap();
+
+        let mut first = String::new();
+        BufReader::new(child.stdout.as_mut().unwrap()).read_line(&mut first).unwrap();
+        let path = first.trim().strip_prefix("Simulating a soup device at ").unwrap().to_string();
+
+        Self { child, path }
+    }
+
+    /// Run soup-cli against the simulated device, and return its stdout
+    fn soup(&self, args: &[&str]) -> Result<String, String> {
+        let out = Command::new(SOUP).args(["--port", &self.path]).args(args).output().unwrap();
+        let stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+        match out.status.success() {
+            true => Ok(stdout),
+            false => Err(format!("{stdout}{}", String::from_utf8_lossy(&out.stderr))),
+        }
+    }
+}
+
+impl Drop for Sim {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn tmp(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{}-{name}", std::process::id()))
+}
+
+/// Hand out the lines `stdout` prints, as they come
+fn lines(stdout: ChildStdout) -> Receiver<String> {
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if tx.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// An Intel HEX record
+fn hex_record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    bytes.push(bytes.iter().fold(0u8, |sum, b| sum.wrapping_sub(*b)));
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{digits}\n")
+}
+
+fn wait_for(rx: &Receiver<String>, want: &str) {
+    loop {
+        match rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(line) if line.contains(want) => return,
+            Ok(_) => {}
+            Err(e) => panic!("no {want:?}: {e}"),
+        }
+    }
+}
+
+#[test]
+fn peek_poke() {
+    let sim = Sim::start(&[]);
+
+    sim.soup(&["stage0", "poke", "-a", "20000100", "-b", "0x12,0x34,0x56"]).unwrap();
+    let out = sim.soup(&["stage0", "peek", "-a", "200000FF", "-l", "5"]).unwrap();
+    assert!(out.contains("00 12 34 56 00"), "{out}");
+
+    let err = sim.soup(&["stage0", "peek", "-a", "10000000", "-l", "4"]).unwrap_err();
+    assert!(err.contains("are outside of 0x20000000..0x20038000"), "{err}");
+}
+
+#[test]
+fn flash_poke() {
+    let sim = Sim::start(&[]);
+    let (input, output) = (tmp("flash-in.bin"), tmp("flash-out.bin"));
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+
+    let out = sim.soup(&["stage0", "flash-poke", "-a", "10000", "-f", input.to_str().unwrap()]).unwrap();
+    assert!(out.contains("Verified."), "{out}");
+
+    sim.soup(&["stage0", "flash-peek", "-a", "10000", "-l", "5000", "-f", output.to_str().unwrap()]).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+
+    let err = sim.soup(&["stage0", "flash-poke", "-a", "0", "-f", input.to_str().unwrap()]).unwrap_err();
+    assert!(err.contains("would overwrite the bootloader"), "{err}");
+}
+
+#[test]
+fn flash_poke_windows() {
+    let sim = Sim::start(&[]);
+    let input = tmp("flash-big.bin");
+    // All of the application flash, more than the scratch RAM holds
+    let data: Vec<u8> = (0..992 * 1024u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+
+    let out = sim.soup(&["stage0", "flash-poke", "-a", "8000", "-f", input.to_str().unwrap()]).unwrap();
+    assert_eq!(out.matches("Flashing").count(), 5, "{out}");
+    assert!(out.contains("Flashing 98304 bytes at 0x000E8000"), "{out}");
+
+    // Running it again skips what's already there
+    let out = sim.soup(&["stage0", "flash-poke", "-a", "8000", "-f", input.to_str().unwrap()]).unwrap();
+    assert!(!out.contains("Flashing"), "{out}");
+    assert_eq!(out.matches("is already flashed").count(), 5, "{out}");
+}
+
+#[test]
+fn run_and_stdio() {
+    let sim = Sim::start(&["hello from the sim"]);
+
+    // Just a vector table, the simulated app doesn't run it
+    let addr: u32 = 0x2000_0000;
+    let mut image = vec![0u8; 512];
+    image[0..4].copy_from_slice(&0x2004_0000u32.to_le_bytes());
+    image[4..8].copy_from_slice(&(addr + 0x101).to_le_bytes());
+    let header = ImageHeader::new(addr, addr, AppVersion { major: 1, minor: 2, patch: 3 }, &image);
+    let path = tmp("app.simg");
+    std::fs::write(&path, [&header.to_bytes()[..], &image].concat()).unwrap();
+
+    let mut run = Command::new(SOUP)
+        .args(["--port", &sim.path, "run", path.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let rx = lines(run.stdout.take().unwrap());
+
+    wait_for(&rx, "Bootloading 0x20000000.");
+    wait_for(&rx, "soup app speaks protocol");
+    wait_for(&rx, "hello from the sim");
+
+    // The simulated app echoes stdin
+    run.stdin.as_mut().unwrap().write_all(b"ping\n").unwrap();
+    wait_for(&rx, "ping");
+    run.kill().unwrap();
+    run.wait().unwrap();
+
+    // Getting back to stage0 takes rebooting the app
+    let out = sim.soup(&["stage0", "info"]).unwrap();
+    assert!(out.contains("Commanding reset"), "{out}");
+    assert!(out.contains("Device ID:"), "{out}");
+}
+
+#[test]
+fn run_segments() {
+    let sim = Sim::start(&[]);
+
+    // A vector table in RAM, and data for flash far below it
+    let mut vectors = 0x2004_0000u32.to_le_bytes().to_vec();
+    vectors.extend(0x2000_0101u32.to_le_bytes());
+    let hex = [
+        hex_record(4, 0, &[0x20, 0x00]),
+        hex_record(0, 0, &vectors),
+        hex_record(4, 0, &[0x00, 0x01]),
+        hex_record(0, 0x0010, b"in flash"),
+        hex_record(1, 0, &[]),
+    ]
+    .concat();
+    let path = tmp("segments.hex");
+    std::fs::write(&path, hex).unwrap();
+
+    let mut run = Command::new(SOUP)
+        .args(["--port", &sim.path, "run", path.to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let rx = lines(run.stdout.take().unwrap());
+    wait_for(&rx, "Flashing 4096 bytes at 0x00010000");
+    wait_for(&rx, "Loading 8 bytes to 0x20000000");
+    wait_for(&rx, "Bootloading 0x20000000.");
+    run.kill().unwrap();
+    run.wait().unwrap();
+
+    let out = sim.soup(&["stage0", "flash-peek", "-a", "10010", "-l", "8"]).unwrap();
+    assert!(out.contains("69 6E 20 66 6C 61 73 68"), "{out}");
+
+    // Nowhere stage0 can write
+    std::fs::write(&path, [hex_record(4, 0, &[0x30, 0x00]), hex_record(0, 0, b"?"), hex_record(1, 0, &[])].concat()).unwrap();
+    let err = sim.soup(&["run", path.to_str().unwrap()]).unwrap_err();
+    assert!(err.contains("is neither in scratch RAM"), "{err}");
+}
+
+#[test]
+fn run_from_flash() {
+    let sim = Sim::start(&["hello from flash"]);
+
+    // A vector table linked for slot A, with nothing for RAM
+    let mut image = vec![0u8; 512];
+    image[0..4].copy_from_slice(&0x2004_0000u32.to_le_bytes());
+    image[4..8].copy_from_slice(&0x0000_8101u32.to_le_bytes());
+    let path = tmp("flash-app.bin");
+    std::fs::write(&path, image).unwrap();
+
+    let mut run = Command::new(SOUP)
+        .args(["--port", &sim.path, "run", path.to_str().unwrap(), "--base", "0x8000"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let rx = lines(run.stdout.take().unwrap());
+    wait_for(&rx, "Flashing 4096 bytes at 0x00008000");
+    wait_for(&rx, "Bootloading 0x00008000.");
+    wait_for(&rx, "hello from flash");
+    run.kill().unwrap();
+    run.wait().unwrap();
+}
@@ -0,0 +1,20 @@
+#![no_std]
+
+//! Numbers from the build environment, like the `CARGO_PKG_VERSION_*`
+//! strings or settings in environment variables, parsed at compile time.
+
+/// Parse a decimal number at compile time.
+///
+/// Panics (at compile time, when used in a const) on anything that isn't
+/// a plain decimal number.
+pub const fn parse_decimal(s: &str) -> u64 {
+    let bytes = s.as_bytes();
+    let mut val: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "not a decimal number");
+        val = val * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    val
+}
@@ -1,11 +1,28 @@
 #![cfg_attr(not(any(test, feature = "use-std")), no_std)]
 
 pub use soup_managed::Managed;
+use soup_env::parse_decimal;
 use serde::{Deserialize, Serialize};
 
+/// The version of this ICD crate, exchanged in [`Hello`].
+pub const ICD_VERSION: Version = Version::from_pkg(
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    env!("CARGO_PKG_VERSION_MINOR"),
+    env!("CARGO_PKG_VERSION_PATCH"),
+);
+
+/// Identifies the soup app protocol in a [`Hello`], "SOUP"
+pub const PROTOCOL: u32 = u32::from_le_bytes(*b"SOUP");
+
+/// Everything the host sends to a soup app.
+///
+/// `Hello` must stay the first variant, and [`Hello`] must never change
+/// shape, so that any two versions of the protocol can at least figure
+/// out that they don't match.
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum ToSoup<'a> {
+    Hello(Hello),
     #[serde(borrow)]
     Stdin(Managed<'a>),
     Control(Control),
@@ -15,6 +32,7 @@ pub enum ToSoup<'a> {
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum FromSoup<'a> {
+    Hello(Hello),
     #[serde(borrow)]
     Stdout(Managed<'a>),
     Stderr(Managed<'a>),
@@ -44,3 +62,138 @@ pub enum ControlResponse<'a> {
     #[serde(borrow)]
     AppInfo(Managed<'a>),
 }
+
+/// Sent by the host when it connects, and answered by the app with its own.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Hello {
+    /// Always [`PROTOCOL`] for soup apps
+    pub protocol: u32,
+    pub version: Version,
+}
+
+impl Hello {
+    pub const CURRENT: Self = Self {
+        protocol: PROTOCOL,
+        version: ICD_VERSION,
+    };
+}
+
+/// A semver-ish protocol version.
+///
+/// Peers with the same major version share a wire format. A newer minor
+/// version only ever adds things at the end of an enum, so a host must
+/// check the minor version of the app before using anything newer.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    /// Build a version from the `CARGO_PKG_VERSION_*` strings at compile time.
+    ///
+    /// Panics (at compile time, when used in a const) on anything that isn't
+    /// a plain decimal number.
+    pub const fn from_pkg(major: &str, minor: &str, patch: &str) -> Self {
+        const fn parse(s: &str) -> u16 {
+            let val = parse_decimal(s);
+            assert!(val <= u16::MAX as u64, "version numbers go up to 65535");
+            val as u16
+        }
+
+        Self {
+            major: parse(major),
+            minor: parse(minor),
+            patch: parse(patch),
+        }
+    }
+
+    /// Can we talk to a peer with version `other` at all?
+    pub fn is_compatible(&self, other: &Version) -> bool {
+        self.major == other.major
+    }
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Describe every type in [`ToSoup`] and [`FromSoup`], for tooling that
+/// can't use this crate. See `stage0_icd::schema`.
+#[cfg(any(test, feature = "schema"))]
+pub fn schema() -> serde_reflection::Result<serde_reflection::Registry> {
+    let mut tracer = serde_reflection::Tracer::new(Default::default());
+    tracer.trace_simple_type::<ToSoup<'_>>()?;
+    tracer.trace_simple_type::<FromSoup<'_>>()?;
+    // Enums inside them only get all their variants traced on their own, and
+    // tracing fails until a new one is added here
+    tracer.trace_simple_type::<Control>()?;
+    tracer.trace_simple_type::<ControlResponse<'_>>()?;
+    tracer.trace_simple_type::<Error<'_>>()?;
+    tracer.registry()
+}
+
+#[cfg(test)]
+mod test {
+    //! Golden bytes for every message. If one of these changes, the wire
+    //! format changed, and the version in `Cargo.toml` needs a bump.
+
+    use super::*;
+    use serde::Serialize;
+
+    fn golden<T: Serialize>(val: &T, bytes: &[u8]) {
+        assert_eq!(postcard::to_stdvec(val).unwrap(), bytes, "{}", core::any::type_name::<T>());
+    }
+
+    const HELLO: Hello = Hello { protocol: PROTOCOL, version: Version { major: 3, minor: 1, patch: 2 } };
+    const HELLO_BYTES: &[u8] = &[0xD3, 0x9E, 0xD5, 0x82, 0x05, 0x03, 0x01, 0x02];
+
+    #[test]
+    fn version_matches_cargo() {
+        assert_eq!(ICD_VERSION.to_string(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(Hello::CURRENT.version, ICD_VERSION);
+    }
+
+    #[test]
+    fn hello() {
+        golden(&HELLO, HELLO_BYTES);
+        golden(&ToSoup::Hello(HELLO), &[&[0x00], HELLO_BYTES].concat());
+        golden(&FromSoup::Hello(HELLO), &[&[0x00], HELLO_BYTES].concat());
+    }
+
+    #[test]
+    fn to_soup() {
+        golden(&ToSoup::Stdin(Managed::from_borrowed(b"hi")), &[0x01, 0x02, b'h', b'i']);
+        golden(&ToSoup::Control(Control::Reboot), &[0x02, 0x00]);
+        golden(&ToSoup::Control(Control::SendAppInfo), &[0x02, 0x01]);
+        golden(&ToSoup::ToApp(Managed::from_borrowed(&[0xAA])), &[0x03, 0x01, 0xAA]);
+    }
+
+    #[test]
+    fn from_soup() {
+        golden(&FromSoup::Stdout(Managed::from_borrowed(b"hi")), &[0x01, 0x02, b'h', b'i']);
+        golden(&FromSoup::Stderr(Managed::from_borrowed(b"hi")), &[0x02, 0x02, b'h', b'i']);
+        golden(&FromSoup::ControlResponse(ControlResponse::AppInfo(Managed::from_borrowed(&[0xAA]))), &[0x03, 0x00, 0x01, 0xAA]);
+        golden(&FromSoup::FromApp(Managed::from_borrowed(&[0xAA])), &[0x04, 0x01, 0xAA]);
+        golden(&FromSoup::Error(Error::Other(Managed::from_borrowed(b"x"))), &[0x05, 0x00, 0x01, b'x']);
+        golden(&FromSoup::Error(Error::InvalidMessage), &[0x05, 0x01]);
+    }
+
+    #[test]
+    fn schema() {
+        use serde_reflection::ContainerFormat;
+
+        let registry = super::schema().unwrap();
+        let Some(ContainerFormat::Enum(from_soup)) = registry.get("FromSoup") else {
+            panic!("no FromSoup in {registry:?}");
+        };
+        let names: Vec<_> = from_soup.values().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["Hello", "Stdout", "Stderr", "ControlResponse", "FromApp", "Error"]);
+        assert!(registry.contains_key("ControlResponse"));
+    }
+}
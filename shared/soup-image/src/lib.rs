@@ -0,0 +1,317 @@
+#![cfg_attr(not(any(test, feature = "use-std")), no_std)]
+
+//! The header in front of a soupstone application image
+//!
+//! A packed image file is an [`ImageHeader`] in its [`to_bytes`] form,
+//! directly followed by the image itself. The image gets loaded to
+//! `load_addr`, the header only travels along with it, so the application
+//! doesn't need to leave room for it when linking.
+//!
+//! All fields are little endian, at fixed offsets:
+//!
+//! | offset | field            |
+//! | :----- | :--------------- |
+//! | 0      | `magic`          |
+//! | 4      | `header_version` |
+//! | 8      | `image_len`      |
+//! | 12     | `load_addr`      |
+//! | 16     | `entry`          |
+//! | 20     | `image_crc`      |
+//! | 24     | `app_version`    |
+//! | 30     | zero padding     |
+//! | 32     | `header_crc`     |
+//!
+//! A signed image additionally has a 64 byte Ed25519 [`Signature`] over the
+//! header and the image appended, see [`signature`].
+//!
+//! [`to_bytes`]: ImageHeader::to_bytes
+
+use serde::{Deserialize, Serialize};
+
+pub mod signature;
+
+/// Identifies a soupstone image header, "SIMG"
+pub const MAGIC: u32 = u32::from_le_bytes(*b"SIMG");
+
+/// The only header layout so far
+pub const HEADER_VERSION: u32 = 1;
+
+/// Size of a version 1 header in bytes
+pub const HEADER_SIZE: usize = 36;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct AppVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl core::fmt::Display for AppVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct ImageHeader {
+    /// Always [`MAGIC`]
+    pub magic: u32,
+    /// Always [`HEADER_VERSION`], for now
+    pub header_version: u32,
+    /// Length of the image following the header
+    pub image_len: u32,
+    /// Where the first byte of the image goes
+    pub load_addr: u32,
+    /// Address of the vector table stage0 boots through
+    pub entry: u32,
+    /// CRC-32/ISO-HDLC of the image
+    pub image_crc: u32,
+    pub app_version: AppVersion,
+    /// CRC-32/ISO-HDLC of the first 32 bytes of the header
+    pub header_crc: u32,
+}
+
+/// Why an image or its header was rejected
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum HeaderError {
+    /// There aren't even enough bytes for a header
+    TooShort,
+    BadMagic { magic: u32 },
+    UnsupportedVersion { version: u32 },
+    HeaderCrcMismatch,
+    /// There are fewer bytes than `image_len` says
+    Truncated { len: u32, expected: u32 },
+    ImageCrcMismatch,
+    /// `entry` isn't inside the image
+    EntryOutsideImage { entry: u32 },
+    /// The signature doesn't match the image, or the key, since 1.1.0
+    BadSignature,
+}
+
+impl core::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HeaderError::TooShort => write!(f, "too short for a {HEADER_SIZE} byte image header"),
+            HeaderError::BadMagic { magic } => write!(f, "bad header magic 0x{magic:08X}"),
+            HeaderError::UnsupportedVersion { version } => {
+                write!(f, "unsupported header version {version}")
+            }
+            HeaderError::HeaderCrcMismatch => f.write_str("header CRC mismatch, the header is corrupted"),
+            HeaderError::Truncated { len, expected } => {
+                write!(f, "image is truncated, {len} of {expected} bytes")
+            }
+            HeaderError::ImageCrcMismatch => f.write_str("image CRC mismatch, the image is corrupted"),
+            HeaderError::EntryOutsideImage { entry } => {
+                write!(f, "entry point 0x{entry:08X} is outside of the image")
+            }
+            HeaderError::BadSignature => f.write_str("the image isn't signed with the right key"),
+        }
+    }
+}
+
+#[cfg(feature = "use-std")]
+impl std::error::Error for HeaderError {}
+
+impl ImageHeader {
+    /// A header for `image`, loaded at `load_addr`
+    pub fn new(load_addr: u32, entry: u32, app_version: AppVersion, image: &[u8]) -> Self {
+        let mut hdr = Self {
+            magic: MAGIC,
+            header_version: HEADER_VERSION,
+            image_len: image.len() as u32,
+            load_addr,
+            entry,
+            image_crc: crc32(image),
+            app_version,
+            header_crc: 0,
+        };
+        hdr.header_crc = hdr.calc_header_crc();
+        hdr
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0u8; HEADER_SIZE];
+        out[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        out[4..8].copy_from_slice(&self.header_version.to_le_bytes());
+        out[8..12].copy_from_slice(&self.image_len.to_le_bytes());
+        out[12..16].copy_from_slice(&self.load_addr.to_le_bytes());
+        out[16..20].copy_from_slice(&self.entry.to_le_bytes());
+        out[20..24].copy_from_slice(&self.image_crc.to_le_bytes());
+        out[24..26].copy_from_slice(&self.app_version.major.to_le_bytes());
+        out[26..28].copy_from_slice(&self.app_version.minor.to_le_bytes());
+        out[28..30].copy_from_slice(&self.app_version.patch.to_le_bytes());
+        out[32..36].copy_from_slice(&self.header_crc.to_le_bytes());
+        out
+    }
+
+    /// Parse and [`check`](Self::check) the header at the start of `bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HeaderError> {
+        let bytes = bytes.get(..HEADER_SIZE).ok_or(HeaderError::TooShort)?;
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+
+        let hdr = Self {
+            magic: u32_at(0),
+            header_version: u32_at(4),
+            image_len: u32_at(8),
+            load_addr: u32_at(12),
+            entry: u32_at(16),
+            image_crc: u32_at(20),
+            app_version: AppVersion {
+                major: u16_at(24),
+                minor: u16_at(26),
+                patch: u16_at(28),
+            },
+            header_crc: u32_at(32),
+        };
+
+        // The padding isn't a field, so make sure the CRC still covers it
+        if bytes[30..32] != [0, 0] {
+            return Err(HeaderError::HeaderCrcMismatch);
+        }
+
+        hdr.check()?;
+        Ok(hdr)
+    }
+
+    /// Is this a header we understand, and did it arrive intact?
+    pub fn check(&self) -> Result<(), HeaderError> {
+        if self.magic != MAGIC {
+            return Err(HeaderError::BadMagic { magic: self.magic });
+        }
+        if self.header_version != HEADER_VERSION {
+            return Err(HeaderError::UnsupportedVersion { version: self.header_version });
+        }
+        if self.header_crc != self.calc_header_crc() {
+            return Err(HeaderError::HeaderCrcMismatch);
+        }
+        if !self.contains(self.entry) {
+            return Err(HeaderError::EntryOutsideImage { entry: self.entry });
+        }
+        Ok(())
+    }
+
+    /// Does `image` match this (already checked) header? Anything past
+    /// `image_len` is ignored.
+    pub fn check_image(&self, image: &[u8]) -> Result<(), HeaderError> {
+        let image = image.get(..self.image_len as usize).ok_or(HeaderError::Truncated {
+            len: image.len() as u32,
+            expected: self.image_len,
+        })?;
+        if crc32(image) != self.image_crc {
+            return Err(HeaderError::ImageCrcMismatch);
+        }
+        Ok(())
+    }
+
+    /// Is `addr` inside the loaded image?
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.load_addr && (addr - self.load_addr) < self.image_len
+    }
+
+    fn calc_header_crc(&self) -> u32 {
+        crc32(&self.to_bytes()[..32])
+    }
+}
+
+/// An Ed25519 signature over a header in its [`to_bytes`](ImageHeader::to_bytes)
+/// form, directly followed by the image
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Signature {
+    pub r: [u8; 32],
+    pub s: [u8; 32],
+}
+
+impl Signature {
+    pub const SIZE: usize = 64;
+
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let mut sig = Self { r: [0; 32], s: [0; 32] };
+        sig.r.copy_from_slice(&bytes[..32]);
+        sig.s.copy_from_slice(&bytes[32..]);
+        sig
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[..32].copy_from_slice(&self.r);
+        out[32..].copy_from_slice(&self.s);
+        out
+    }
+}
+
+/// CRC-32/ISO-HDLC, the zlib/PNG one
+pub fn crc32(data: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VER: AppVersion = AppVersion { major: 1, minor: 2, patch: 3 };
+
+    fn packed(image: &[u8]) -> Vec<u8> {
+        let hdr = ImageHeader::new(0x2000_0000, 0x2000_0000, VER, image);
+        [&hdr.to_bytes()[..], image].concat()
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = [0xAAu8; 100];
+        let file = packed(&image);
+        let hdr = ImageHeader::from_bytes(&file).unwrap();
+
+        assert_eq!(hdr.image_len, 100);
+        assert_eq!(hdr.load_addr, 0x2000_0000);
+        assert_eq!(hdr.app_version, VER);
+        assert_eq!(hdr.check_image(&file[HEADER_SIZE..]), Ok(()));
+    }
+
+    #[test]
+    fn layout() {
+        let hdr = ImageHeader::new(0x8000, 0x8000, VER, b"123456789");
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[0..4], b"SIMG");
+        assert_eq!(&bytes[8..12], &[9, 0, 0, 0]);
+        // The CRC-32/ISO-HDLC check value
+        assert_eq!(&bytes[20..24], &0xCBF4_3926u32.to_le_bytes());
+        assert_eq!(&bytes[24..30], &[1, 0, 2, 0, 3, 0]);
+    }
+
+    #[test]
+    fn rejects_corrupted_header() {
+        let mut file = packed(&[0xAA; 100]);
+        assert_eq!(ImageHeader::from_bytes(&file[..HEADER_SIZE - 1]), Err(HeaderError::TooShort));
+
+        file[12] ^= 0x01;
+        assert_eq!(ImageHeader::from_bytes(&file), Err(HeaderError::HeaderCrcMismatch));
+
+        file[0] = b'X';
+        assert!(matches!(ImageHeader::from_bytes(&file), Err(HeaderError::BadMagic { .. })));
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let mut file = packed(&[0xAA; 100]);
+        let hdr = ImageHeader::from_bytes(&file).unwrap();
+
+        assert_eq!(
+            hdr.check_image(&file[HEADER_SIZE..][..99]),
+            Err(HeaderError::Truncated { len: 99, expected: 100 })
+        );
+
+        file[HEADER_SIZE + 50] = 0x55;
+        assert_eq!(hdr.check_image(&file[HEADER_SIZE..]), Err(HeaderError::ImageCrcMismatch));
+    }
+
+    #[test]
+    fn rejects_entry_outside_image() {
+        let hdr = ImageHeader::new(0x2000_0000, 0x2000_0100, VER, &[0; 0x100]);
+        assert_eq!(hdr.check(), Err(HeaderError::EntryOutsideImage { entry: 0x2000_0100 }));
+    }
+}
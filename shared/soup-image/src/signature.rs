@@ -0,0 +1,136 @@
+//! Ed25519 signatures over images
+//!
+//! The signed message is the header in its [`to_bytes`] form, directly
+//! followed by the image, so a signature also covers the load address, the
+//! entry point and the version.
+//!
+//! Keys are the raw 32 byte forms: the secret seed, and the public key.
+//!
+//! [`to_bytes`]: ImageHeader::to_bytes
+
+use ed25519_compact::{KeyPair, PublicKey, Seed};
+
+use crate::{HeaderError, ImageHeader, Signature};
+
+/// Check that `signature` was made over `header` and `image` with the secret
+/// key belonging to `public_key`.
+///
+/// Only the first `header.image_len` bytes of `image` are covered.
+pub fn verify(
+    public_key: &[u8; 32],
+    header: &ImageHeader,
+    image: &[u8],
+    signature: &Signature,
+) -> Result<(), HeaderError> {
+    let image = image.get(..header.image_len as usize).ok_or(HeaderError::Truncated {
+        len: image.len() as u32,
+        expected: header.image_len,
+    })?;
+    verify_parts(public_key, &[&header.to_bytes(), image], signature)
+}
+
+/// Check `signature` over the concatenation of `parts`, without having to
+/// concatenate them
+pub fn verify_parts(public_key: &[u8; 32], parts: &[&[u8]], signature: &Signature) -> Result<(), HeaderError> {
+    let sig = ed25519_compact::Signature::new(signature.to_bytes());
+    let mut state = PublicKey::new(*public_key)
+        .verify_incremental(&sig)
+        .map_err(|_| HeaderError::BadSignature)?;
+    parts.iter().for_each(|part| state.absorb(part));
+    state.verify().map_err(|_| HeaderError::BadSignature)
+}
+
+/// The public key belonging to the secret `seed`
+pub fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    *KeyPair::from_seed(Seed::new(*seed)).pk
+}
+
+/// Sign `header` and `image` with the secret `seed`
+#[cfg(any(test, feature = "use-std"))]
+pub fn sign(seed: &[u8; 32], header: &ImageHeader, image: &[u8]) -> Signature {
+    let image = &image[..header.image_len as usize];
+    let message = [&header.to_bytes()[..], image].concat();
+    let sig = KeyPair::from_seed(Seed::new(*seed)).sk.sign(message, None);
+    Signature::from_bytes(&sig)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AppVersion;
+
+    fn hex<const N: usize>(s: &str) -> [u8; N] {
+        let mut out = [0u8; N];
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..][..2], 16).unwrap();
+        }
+        out
+    }
+
+    struct Vector {
+        seed: &'static str,
+        public: &'static str,
+        message: &'static [u8],
+        signature: &'static str,
+    }
+
+    // RFC 8032, section 7.1, TEST 1 and TEST 2
+    const VECTORS: &[Vector] = &[
+        Vector {
+            seed: "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            public: "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            message: &[],
+            signature: "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        },
+        Vector {
+            seed: "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            public: "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            message: &[0x72],
+            signature: "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        },
+    ];
+
+    #[test]
+    fn rfc8032_vectors() {
+        for v in VECTORS {
+            let public = hex::<32>(v.public);
+            let sig = Signature::from_bytes(&hex::<64>(v.signature));
+
+            assert_eq!(public_key(&hex::<32>(v.seed)), public);
+            assert_eq!(verify_parts(&public, &[v.message], &sig), Ok(()));
+
+            // Splitting the message up doesn't change anything
+            let (a, b) = v.message.split_at(v.message.len() / 2);
+            assert_eq!(verify_parts(&public, &[a, &[], b], &sig), Ok(()));
+
+            let mut bad = sig;
+            bad.s[0] ^= 0x01;
+            assert_eq!(verify_parts(&public, &[v.message], &bad), Err(HeaderError::BadSignature));
+        }
+    }
+
+    #[test]
+    fn images() {
+        let seed = hex::<32>(VECTORS[0].seed);
+        let public = public_key(&seed);
+        let other_public = public_key(&hex::<32>(VECTORS[1].seed));
+
+        let image = [0xAAu8; 100];
+        let header = ImageHeader::new(0x8000, 0x8000, AppVersion { major: 1, minor: 2, patch: 3 }, &image);
+        let sig = sign(&seed, &header, &image);
+
+        assert_eq!(verify(&public, &header, &image, &sig), Ok(()));
+        assert_eq!(verify(&other_public, &header, &image, &sig), Err(HeaderError::BadSignature));
+
+        // Anything after the image isn't covered
+        let longer = [&image[..], &[0x55; 4]].concat();
+        assert_eq!(verify(&public, &header, &longer, &sig), Ok(()));
+
+        let mut tampered = image;
+        tampered[50] = 0x55;
+        assert_eq!(verify(&public, &header, &tampered, &sig), Err(HeaderError::BadSignature));
+
+        let moved = ImageHeader::new(0x9000, 0x9000, header.app_version, &image);
+        assert_eq!(verify(&public, &moved, &image, &sig), Err(HeaderError::BadSignature));
+    }
+}
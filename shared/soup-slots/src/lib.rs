@@ -0,0 +1,532 @@
+#![cfg_attr(not(any(test, feature = "use-std")), no_std)]
+
+//! A/B flash slots, with trial boots and automatic rollback
+//!
+//! The flash above stage0 holds two application slots, and a page of slot
+//! state. One slot is *active*: it holds the last image known to work. A new
+//! image goes into the other slot, which is then booted on *trial*. If the
+//! new image doesn't [confirm](Slots::confirm) itself before the next reset,
+//! stage0 goes back to the active slot.
+//!
+//! The state lives in two pages, each a log of [`State`] records appended
+//! on every change, so that changing state doesn't need an erase until a
+//! page is full. Then the log moves on to the other page, under the next
+//! sequence number, and the newest page counts. The full page is only erased
+//! once the log comes back around to it, so the last state recorded is
+//! always somewhere in flash. A record torn by a reset fails its CRC, and the
+//! one before it still counts.
+//!
+//! Images are linked for the slot they run from, so a new image has to be
+//! built for whichever slot is inactive.
+
+use embedded_storage::nor_flash::NorFlash;
+use serde::{Deserialize, Serialize};
+
+/// Where the slots and their state live in flash
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Layout {
+    pub slot_a: u32,
+    pub slot_b: u32,
+    pub slot_size: u32,
+    /// The state pages, two of `meta_size / 2` bytes
+    pub meta: u32,
+    pub meta_size: u32,
+}
+
+/// The nRF52840 layout: two 492K slots after stage0's 32K, and the state
+/// pages in the last 8K
+pub const NRF52840: Layout = Layout {
+    slot_a: 0x0000_8000,
+    slot_b: 0x0008_3000,
+    slot_size: 0x7_B000,
+    meta: 0x000F_E000,
+    meta_size: 0x2000,
+};
+
+impl Layout {
+    pub fn slot_addr(&self, slot: Slot) -> u32 {
+        match slot {
+            Slot::A => self.slot_a,
+            Slot::B => self.slot_b,
+        }
+    }
+
+    /// Which slot, if any, is `addr` in?
+    pub fn slot_of(&self, addr: u32) -> Option<Slot> {
+        [Slot::A, Slot::B].into_iter().find(|slot| {
+            let start = self.slot_addr(*slot);
+            addr >= start && (addr - start) < self.slot_size
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// A slot holding a new image that hasn't confirmed itself yet
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Trial {
+    pub slot: Slot,
+    /// Has the trial image been booted? Its one chance is used up then.
+    pub booted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct State {
+    /// The slot with the last image known to work
+    pub active: Slot,
+    pub trial: Option<Trial>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    Flash(E),
+    /// A trial has to use the inactive slot
+    SlotIsActive,
+}
+
+impl State {
+    /// Before anything was ever recorded
+    pub const DEFAULT: Self = Self { active: Slot::A, trial: None };
+
+    /// Put a new image in `slot` on trial
+    pub fn start_trial(self, slot: Slot) -> Result<State, Error<()>> {
+        if slot == self.active {
+            return Err(Error::SlotIsActive);
+        }
+        Ok(State { active: self.active, trial: Some(Trial { slot, booted: false }) })
+    }
+
+    /// Pick the slot to boot, and the state to record before booting it
+    pub fn boot(self) -> (State, Slot) {
+        match self.trial {
+            // Its one chance
+            Some(Trial { slot, booted: false }) => {
+                (State { active: self.active, trial: Some(Trial { slot, booted: true }) }, slot)
+            }
+            // It had its chance, and didn't confirm. Roll back.
+            Some(Trial { booted: true, .. }) => (State { active: self.active, trial: None }, self.active),
+            None => (self, self.active),
+        }
+    }
+
+    /// The image running from `running` works. Only does something for a
+    /// trial image that was booted.
+    pub fn confirm(self, running: Slot) -> State {
+        match self.trial {
+            Some(Trial { slot, booted: true }) if slot == running => State { active: slot, trial: None },
+            _ => self,
+        }
+    }
+
+    fn to_record(self, seq: u32) -> [u8; RECORD_SIZE] {
+        let slot_byte = |slot: Slot| match slot {
+            Slot::A => 0,
+            Slot::B => 1,
+        };
+
+        let mut out = [0u8; RECORD_SIZE];
+        out[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        out[4] = slot_byte(self.active);
+        if let Some(trial) = self.trial {
+            out[5] = 1 + slot_byte(trial.slot);
+            out[6] = trial.booted as u8;
+        }
+        out[8..12].copy_from_slice(&seq.to_le_bytes());
+        let crc = crc32(&out[..12]);
+        out[12..16].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// The state in a record, and the sequence number of its page
+    fn from_record(rec: &[u8; RECORD_SIZE]) -> Option<(State, u32)> {
+        let crc = u32::from_le_bytes([rec[12], rec[13], rec[14], rec[15]]);
+        if rec[0..4] != RECORD_MAGIC.to_le_bytes() || crc != crc32(&rec[..12]) {
+            return None;
+        }
+
+        let slot = |byte: u8| match byte {
+            0 => Some(Slot::A),
+            1 => Some(Slot::B),
+            _ => None,
+        };
+        let trial = match rec[5] {
+            0 => None,
+            n => Some(Trial { slot: slot(n - 1)?, booted: rec[6] != 0 }),
+        };
+        let seq = u32::from_le_bytes([rec[8], rec[9], rec[10], rec[11]]);
+        Some((State { active: slot(rec[4])?, trial }, seq))
+    }
+}
+
+/// One record in the state log:
+///
+/// | offset | field                           |
+/// | :----- | :------------------------------ |
+/// | 0      | magic, "SLOT"                   |
+/// | 4      | active slot, 0 = A, 1 = B       |
+/// | 5      | trial slot, 0 = none, 1 + slot  |
+/// | 6      | trial booted, 0 or 1            |
+/// | 7      | zero                            |
+/// | 8      | sequence number of the page     |
+/// | 12     | CRC-32/ISO-HDLC of bytes 0..12  |
+const RECORD_SIZE: usize = 16;
+const RECORD_MAGIC: u32 = u32::from_le_bytes(*b"SLOT");
+
+fn crc32(data: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+}
+
+/// The slot state, as recorded in flash
+pub struct Slots<F> {
+    flash: F,
+    layout: Layout,
+    state: State,
+    /// The state page the log is in, 0 or 1
+    page: u32,
+    /// Its sequence number
+    seq: u32,
+    /// Offset of the next record in that page
+    next: u32,
+}
+
+/// What one state page holds
+struct Page {
+    /// The last state in it, and the page's sequence number
+    last: Option<(State, u32)>,
+    /// Offset of the next record
+    next: u32,
+}
+
+impl<F: NorFlash> Slots<F> {
+    /// Read the current state from the newest state page. Addresses in
+    /// `layout` are `flash` offsets.
+    pub fn load(mut flash: F, layout: Layout) -> Result<Self, F::Error> {
+        let pages = [read_page(&mut flash, layout, 0)?, read_page(&mut flash, layout, 1)?];
+        let newest = match (pages[0].last, pages[1].last) {
+            (Some((_, seq0)), Some((_, seq1))) if seq1 > seq0 => 1,
+            (None, Some(_)) => 1,
+            _ => 0,
+        };
+        let (state, seq) = pages[newest].last.unwrap_or((State::DEFAULT, 0));
+
+        Ok(Self { flash, layout, state, page: newest as u32, seq, next: pages[newest].next })
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Put the (already written) image in `slot` on trial, for the next boot
+    pub fn start_trial(&mut self, slot: Slot) -> Result<(), Error<F::Error>> {
+        let state = self.state.start_trial(slot).map_err(|_| Error::SlotIsActive)?;
+        self.store(state).map_err(Error::Flash)
+    }
+
+    /// Pick the slot to boot now, and record that it was booted
+    pub fn boot(&mut self) -> Result<Slot, F::Error> {
+        let (state, slot) = self.state.boot();
+        self.store(state)?;
+        Ok(slot)
+    }
+
+    /// Called by the image running from `running`, once it's sure it works
+    pub fn confirm(&mut self, running: Slot) -> Result<(), F::Error> {
+        let state = self.state.confirm(running);
+        self.store(state)
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn store(&mut self, state: State) -> Result<(), F::Error> {
+        if state == self.state {
+            return Ok(());
+        }
+
+        // Move on to the other page, which only holds older states
+        if (self.next as usize + RECORD_SIZE) > page_size(self.layout) as usize {
+            let page = 1 - self.page;
+            let start = page_addr(self.layout, page);
+            self.flash.erase(start, start + page_size(self.layout))?;
+            self.page = page;
+            self.seq += 1;
+            self.next = 0;
+        }
+
+        let addr = page_addr(self.layout, self.page) + self.next;
+        self.flash.write(addr, &state.to_record(self.seq))?;
+        self.next += RECORD_SIZE as u32;
+        self.state = state;
+        Ok(())
+    }
+}
+
+fn page_size(layout: Layout) -> u32 {
+    layout.meta_size / 2
+}
+
+fn page_addr(layout: Layout, page: u32) -> u32 {
+    layout.meta + page * page_size(layout)
+}
+
+fn read_page<F: NorFlash>(flash: &mut F, layout: Layout, page: u32) -> Result<Page, F::Error> {
+    let mut last = None;
+    let mut next = 0;
+
+    while (next + RECORD_SIZE as u32) <= page_size(layout) {
+        let mut rec = [0u8; RECORD_SIZE];
+        flash.read(page_addr(layout, page) + next, &mut rec)?;
+        if rec == [0xFF; RECORD_SIZE] {
+            break;
+        }
+        if let Some(r) = State::from_record(&rec) {
+            last = Some(r);
+        }
+        next += RECORD_SIZE as u32;
+    }
+
+    Ok(Page { last, next })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    /// Flash that behaves like NOR: writes only clear bits
+    struct MockFlash {
+        mem: Vec<u8>,
+        erases: usize,
+        /// Lose power right after the next erase
+        cut_after_erase: bool,
+        /// The power is gone, nothing gets written
+        dead: bool,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            Self { mem: vec![0xFF; 1024 * 1024], erases: 0, cut_after_erase: false, dead: false }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.mem[offset..][..bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 4096;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            if self.dead {
+                return Err(NorFlashErrorKind::Other);
+            }
+            self.mem[from as usize..to as usize].fill(0xFF);
+            self.erases += 1;
+            self.dead = self.cut_after_erase;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            if self.dead {
+                return Err(NorFlashErrorKind::Other);
+            }
+            let offset = offset as usize;
+            self.mem[offset..][..bytes.len()]
+                .iter_mut()
+                .zip(bytes)
+                .for_each(|(m, b)| *m &= b);
+            Ok(())
+        }
+    }
+
+    fn load(flash: MockFlash) -> Slots<MockFlash> {
+        Slots::load(flash, NRF52840).unwrap()
+    }
+
+    /// Reset the device: forget everything but the flash
+    fn reset(slots: Slots<MockFlash>) -> Slots<MockFlash> {
+        let mut flash = slots.release();
+        (flash.dead, flash.cut_after_erase) = (false, false);
+        load(flash)
+    }
+
+    #[test]
+    fn layout() {
+        let l = NRF52840;
+        assert_eq!(l.slot_a + l.slot_size, l.slot_b);
+        assert_eq!(l.slot_b + l.slot_size, l.meta);
+        assert!(l.meta + l.meta_size <= 1024 * 1024);
+        assert_eq!(l.slot_of(0x8000), Some(Slot::A));
+        assert_eq!(l.slot_of(0x8_2FFF), Some(Slot::A));
+        assert_eq!(l.slot_of(0x8_3000), Some(Slot::B));
+        assert_eq!(l.slot_of(l.meta), None);
+        assert_eq!(l.slot_of(0x2000_0000), None);
+    }
+
+    #[test]
+    fn transitions() {
+        let s = State::DEFAULT;
+        assert_eq!(s.start_trial(Slot::A), Err(Error::SlotIsActive));
+
+        // Nothing on trial: boot the active slot, nothing changes
+        assert_eq!(s.boot(), (s, Slot::A));
+
+        // Trial, booted once
+        let trial = s.start_trial(Slot::B).unwrap();
+        let (booted, slot) = trial.boot();
+        assert_eq!(slot, Slot::B);
+        assert_eq!(booted.trial, Some(Trial { slot: Slot::B, booted: true }));
+
+        // Confirming only counts from the trial slot, after it was booted
+        assert_eq!(trial.confirm(Slot::B), trial);
+        assert_eq!(booted.confirm(Slot::A), booted);
+        assert_eq!(booted.confirm(Slot::B), State { active: Slot::B, trial: None });
+
+        // No confirm: roll back on the next boot
+        assert_eq!(booted.boot(), (State::DEFAULT, Slot::A));
+    }
+
+    #[test]
+    fn trial_confirmed() {
+        let mut slots = load(MockFlash::new());
+        slots.start_trial(Slot::B).unwrap();
+
+        let mut slots = reset(slots);
+        assert_eq!(slots.boot().unwrap(), Slot::B);
+
+        // The new image runs, and confirms itself
+        let mut slots = reset(slots);
+        slots.confirm(Slot::B).unwrap();
+
+        let mut slots = reset(slots);
+        assert_eq!(slots.state(), State { active: Slot::B, trial: None });
+        assert_eq!(slots.boot().unwrap(), Slot::B);
+    }
+
+    #[test]
+    fn trial_rolled_back() {
+        let mut slots = load(MockFlash::new());
+        slots.start_trial(Slot::B).unwrap();
+        assert_eq!(slots.boot().unwrap(), Slot::B);
+
+        // The new image crashes before confirming
+        let mut slots = reset(slots);
+        assert_eq!(slots.boot().unwrap(), Slot::A);
+
+        let mut slots = reset(slots);
+        assert_eq!(slots.state(), State::DEFAULT);
+        assert_eq!(slots.boot().unwrap(), Slot::A);
+    }
+
+    #[test]
+    fn torn_record() {
+        let mut slots = load(MockFlash::new());
+        slots.start_trial(Slot::B).unwrap();
+        slots.boot().unwrap();
+
+        // A reset in the middle of writing the confirmation
+        let mut flash = slots.release();
+        let meta = NRF52840.meta as usize;
+        flash.mem[meta + 2 * RECORD_SIZE..][..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+
+        let mut slots = load(flash);
+        assert_eq!(slots.state().trial, Some(Trial { slot: Slot::B, booted: true }));
+        assert_eq!(slots.boot().unwrap(), Slot::A);
+
+        // The torn record is skipped for good
+        let slots = reset(slots);
+        assert_eq!(slots.state(), State::DEFAULT);
+    }
+
+    #[test]
+    fn log_wraps() {
+        let mut slots = load(MockFlash::new());
+        let mut active = Slot::A;
+
+        // Way more records than fit in a page
+        for _ in 0..200 {
+            slots.start_trial(active.other()).unwrap();
+            slots = reset(slots);
+            slots.boot().unwrap();
+            slots = reset(slots);
+            slots.confirm(active.other()).unwrap();
+            active = active.other();
+
+            slots = reset(slots);
+            assert_eq!(slots.state(), State { active, trial: None });
+        }
+
+        let flash = slots.release();
+        assert_eq!(flash.erases, 600 / (4096 / RECORD_SIZE));
+    }
+
+    #[test]
+    fn power_cut_switching_pages() {
+        let mut slots = load(MockFlash::new());
+        let mut active = Slot::A;
+
+        // Around both pages, and up to the end of one
+        while slots.seq < 2 || (slots.next as usize + RECORD_SIZE) <= page_size(NRF52840) as usize {
+            slots.start_trial(active.other()).unwrap();
+            slots.boot().unwrap();
+            slots.confirm(active.other()).unwrap();
+            active = active.other();
+        }
+        let committed = State { active, trial: None };
+        assert_eq!(slots.state(), committed);
+
+        // The power goes after erasing the other page, before the record
+        // that needed the room is written
+        let mut flash = slots.release();
+        flash.cut_after_erase = true;
+        let mut slots = load(flash);
+        assert!(slots.start_trial(active.other()).is_err());
+
+        let mut slots = reset(slots);
+        assert_eq!(slots.state(), committed);
+        assert_eq!(slots.boot().unwrap(), active);
+
+        // And it carries on from there
+        slots.start_trial(active.other()).unwrap();
+        let slots = reset(slots);
+        assert_eq!(slots.state().trial, Some(Trial { slot: active.other(), booted: false }));
+    }
+}
@@ -0,0 +1,194 @@
+//! What the fuzz targets do, with a stage0 on mock hardware to run requests on
+//!
+//! Everything here runs on whatever bytes arrive over USB, so none of it may
+//! panic. Out of bounds accesses to the mock RAM and flash are slice indexing,
+//! which panics too.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use postcard::accumulator::{CobsAccumulator, FeedResult};
+use soup_icd::{FromSoup, ToSoup};
+use stage0_core::{frame::Frames, Board, Config, Flash, Memory, Stage0};
+use stage0_icd::{FromStage0, MemRange, ToStage0, Version};
+
+/// The same as the firmware's
+const ACC_SIZE: usize = 512;
+
+/// Decode everything in `$data` as `$ty`s, and make sure what decodes
+/// survives a round trip
+macro_rules! accumulate {
+    ($data:expr, $ty:ident) => {{
+        let mut acc = CobsAccumulator::<ACC_SIZE>::new();
+        let mut window: &[u8] = $data;
+
+        while !window.is_empty() {
+            window = match acc.feed_ref::<$ty<'_>>(window) {
+                FeedResult::Consumed => break,
+                FeedResult::OverFull(rest) | FeedResult::DeserError(rest) => rest,
+                FeedResult::Success { data, remaining } => {
+                    let bytes = postcard::to_stdvec(&data).unwrap();
+                    let again = postcard::from_bytes::<$ty<'_>>(&bytes).expect("can't decode what it encoded");
+                    assert_eq!(postcard::to_stdvec(&again).unwrap(), bytes);
+                    remaining
+                }
+            };
+        }
+    }};
+}
+
+/// Feed a byte stream to stage0 a frame at a time, like the firmware does,
+/// and check that every response decodes and answers the right request
+pub fn stage0_handler(data: &[u8]) {
+    let mut stage0 = stage0();
+    let mut frames = Frames::<ACC_SIZE>::new();
+    let mut outbuf = [0u8; 512];
+
+    for &byte in data {
+        let Some((raw, broken)) = frames.push(byte) else {
+            continue;
+        };
+        let peeked = stage0_icd::peek_request(raw);
+        let (resp, _after) = stage0.handle_frame(raw, broken, &mut outbuf);
+
+        // Only quiet pokes that worked go unanswered
+        if resp.is_empty() {
+            continue;
+        }
+        let mut resp = resp.to_vec();
+        match postcard::from_bytes_cobs::<FromStage0<'_>>(&mut resp).expect("stage0 sent garbage") {
+            FromStage0::Response(header, _) => assert_eq!(Some(header), peeked.map(|(h, _)| h)),
+            FromStage0::FrameError(_) => assert!(peeked.is_none()),
+            FromStage0::Hello(_) => {}
+        }
+    }
+}
+
+/// stage0 messages, through the accumulator soup-cli uses, and the header
+/// peeking stage0 uses to answer frames it can't decode
+pub fn stage0_icd(data: &[u8]) {
+    accumulate!(data, ToStage0);
+    accumulate!(data, FromStage0);
+
+    for frame in data.split(|b| *b == 0x00) {
+        let peeked = stage0_icd::peek_request(frame);
+        if let Ok(ToStage0::Request(header, _)) = postcard::from_bytes_cobs::<ToStage0<'_>>(&mut frame.to_vec()) {
+            assert_eq!(peeked.map(|(h, _)| h), Some(header));
+        }
+    }
+}
+
+/// soup app messages, through the accumulator soup-stuff and soup-cli use
+pub fn soup_icd(data: &[u8]) {
+    accumulate!(data, ToSoup);
+    accumulate!(data, FromSoup);
+}
+
+pub struct Ram {
+    start: usize,
+    mem: Vec<u8>,
+}
+
+impl Memory for Ram {
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.mem
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+}
+
+pub struct MockFlash {
+    mem: Vec<u8>,
+}
+
+impl ErrorType for MockFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(self.mem.get(offset..offset + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.mem.get_mut(from as usize..to as usize).ok_or(NorFlashErrorKind::OutOfBounds)?.fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let mem = self.mem.get_mut(offset..offset + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?;
+        mem.iter_mut().zip(bytes).for_each(|(m, b)| *m &= b);
+        Ok(())
+    }
+}
+
+impl Flash for MockFlash {
+    fn slice(&self, addr: usize, len: usize) -> &[u8] {
+        &self.mem[addr..][..len]
+    }
+}
+
+/// Calls return without running anything
+pub struct MockBoard;
+
+impl Board for MockBoard {
+    fn device_id(&self) -> u64 {
+        0
+    }
+
+    unsafe fn call(&mut self, _addr: u32, args: [u32; 4]) -> u32 {
+        args[0]
+    }
+}
+
+/// A stage0 with the nRF52840's memory layout
+pub fn stage0() -> Stage0<Ram, Ram, MockFlash, MockBoard> {
+    Stage0::new(
+        Ram { start: 0x2000_0000, mem: vec![0; 224 * 1024] },
+        Ram { start: 0x2003_FFC0, mem: vec![0; 64] },
+        MockFlash { mem: vec![0xFF; 1024 * 1024] },
+        MockBoard,
+        Config {
+            version: Version { major: 2, minor: 0, patch: 0 },
+            ram: MemRange { start: 0x2000_0000, len: 256 * 1024 },
+            bootloader_size: 32 * 1024,
+            vtor_align: 256,
+            slots: soup_slots::NRF52840,
+            public_key: None,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use stage0_icd::{Header, Request, ToStage0};
+
+    fn frame(req: Request<'_>) -> Vec<u8> {
+        postcard::to_stdvec_cobs(&ToStage0::Request(Header { seq: 0 }, req)).unwrap()
+    }
+
+    /// Inputs that made the targets panic once
+    #[test]
+    fn regressions() {
+        // Erased past the end of the address space
+        super::stage0_handler(&frame(Request::FlashCopy { ram_start: 0x2000_0000, flash_start: 0xFFFF_F000, len: 1 }));
+    }
+}
@@ -0,0 +1,51 @@
+//! Collecting COBS frames from the host, keeping their raw bytes so that a
+//! frame that can't be handled can still be answered
+
+use stage0_icd::Error;
+
+pub struct Frames<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    /// Why the frame coming in is broken already, if it is
+    broken: Option<Error>,
+}
+
+impl<const N: usize> Frames<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], len: 0, broken: None }
+    }
+
+    /// Part of the frame coming in got lost, e.g. a USB packet we couldn't take
+    pub fn lost(&mut self) {
+        self.broken = Some(Error::Malformed);
+    }
+
+    /// Add a byte. Once the frame is complete, returns its raw bytes, and
+    /// why it's broken if it is.
+    pub fn push(&mut self, byte: u8) -> Option<(&mut [u8], Option<Error>)> {
+        if byte != 0x00 {
+            match self.buf.get_mut(self.len) {
+                Some(b) => {
+                    *b = byte;
+                    self.len += 1;
+                }
+                None => self.broken = Some(Error::FrameTooLarge { max: N }),
+            }
+            return None;
+        }
+
+        // Stray zeroes between frames are fine
+        let len = core::mem::take(&mut self.len);
+        let broken = self.broken.take();
+        if len == 0 && broken.is_none() {
+            return None;
+        }
+        Some((&mut self.buf[..len], broken))
+    }
+}
+
+impl<const N: usize> Default for Frames<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
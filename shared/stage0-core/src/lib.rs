@@ -0,0 +1,555 @@
+#![cfg_attr(not(any(test, feature = "use-std")), no_std)]
+
+//! Everything stage0 does, minus the hardware
+//!
+//! [`Stage0`] handles requests on top of a few traits: [`Memory`] for the
+//! scratch and magic RAM, [`Flash`] for the internal flash, and [`Board`] for
+//! the rest. `firmware/stage0` implements them for the nRF52840, and feeds
+//! it what arrives over USB.
+
+use embedded_storage::nor_flash::NorFlash;
+use sha2::Digest;
+use soup_slots::{Error as SlotError, Slots};
+use stage0_icd::{
+    Crc32, DeviceInfo, Error, FromStage0, Header, Hello, ImageHeader, InvalidImageReason, Managed, MemRange, PeekBytes,
+    Poked, RebootTarget, Request, Response, Sha256, Signature, Slot, Slots as SlotsInfo, Synced, ToStage0,
+    UnalignedFlashAddr, Version, ICD_VERSION,
+};
+
+pub mod frame;
+pub mod magic;
+
+/// RAM stage0 hands out to the host
+pub trait Memory {
+    /// Device address of the first byte
+    fn start(&self) -> usize;
+    fn as_slice(&self) -> &[u8];
+    fn as_mut_slice(&mut self) -> &mut [u8];
+
+    fn range(&self) -> MemRange {
+        MemRange { start: self.start(), len: self.as_slice().len() }
+    }
+}
+
+/// Internal flash, which can also be read directly. It starts at address
+/// zero, and runs for `capacity()` bytes.
+pub trait Flash: NorFlash {
+    /// `len` bytes at `addr`, which stage0 has already checked are in range
+    fn slice(&self, addr: usize, len: usize) -> &[u8];
+}
+
+/// Everything else stage0 needs from the chip
+pub trait Board {
+    /// The unique ID reported in `GetInfo`
+    fn device_id(&self) -> u64;
+
+    /// Call the Thumb function at `addr`, with `args` in `r0`..`r3`, and
+    /// return its `r0`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` has to hold a function, which is only up to the host. stage0
+    /// only makes sure it's in scratch RAM.
+    unsafe fn call(&mut self, addr: u32, args: [u32; 4]) -> u32;
+}
+
+/// What makes one stage0 different from the next
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Version of the stage0 firmware
+    pub version: Version,
+    /// All of RAM, where initial stack pointers have to point
+    pub ram: MemRange,
+    /// Flash holding stage0 itself, from address zero
+    pub bootloader_size: usize,
+    /// Alignment VTOR needs for a vector table
+    pub vtor_align: u32,
+    pub slots: soup_slots::Layout,
+    /// Only run and flash images signed with the secret key belonging to
+    /// this one, if set
+    pub public_key: Option<[u8; 32]>,
+}
+
+/// Things that have to wait until the response has been sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum After {
+    Nothing,
+    /// Reset, and boot whatever the magic says
+    Reset,
+}
+
+pub struct Stage0<S, M, F, B> {
+    pub scratch: S,
+    pub magic: M,
+    pub flash: F,
+    pub board: B,
+    pub config: Config,
+    /// `PokeBytesQuiet`s that succeeded since the last `Sync`
+    quiet_pokes: u32,
+    /// The scratch RAM poked since stage0 started, from the lowest poke to
+    /// the end of the highest. That's the image, as far as `Bootload` knows.
+    poked: Option<MemRange>,
+}
+
+impl<S: Memory, M: Memory, F: Flash, B: Board> Stage0<S, M, F, B> {
+    pub fn new(scratch: S, magic: M, flash: F, board: B, config: Config) -> Self {
+        Self { scratch, magic, flash, board, config, quiet_pokes: 0, poked: None }
+    }
+
+    /// Handle one raw COBS frame from [`frame::Frames`], or answer with why
+    /// it can't be handled
+    pub fn handle_frame<'a>(&mut self, raw: &mut [u8], broken: Option<Error>, outbuf: &'a mut [u8]) -> (&'a [u8], After) {
+        let peeked = stage0_icd::peek_request(raw);
+
+        let err = match broken {
+            Some(err) => err,
+            None => match postcard::from_bytes_cobs::<ToStage0<'_>>(raw) {
+                Ok(ToStage0::Hello(_)) => {
+                    return (encode(&FromStage0::Hello(Hello::CURRENT), outbuf), After::Nothing);
+                }
+                Ok(ToStage0::Request(header, req)) => return self.handle(header, req, outbuf),
+                Err(_) => match peeked {
+                    Some((_, variant)) if variant >= Request::VARIANTS => Error::UnknownRequest { variant },
+                    _ => Error::Malformed,
+                },
+            },
+        };
+
+        let msg = match peeked {
+            Some((header, _)) => FromStage0::Response(header, Err(err)),
+            None => FromStage0::FrameError(err),
+        };
+        (encode(&msg, outbuf), After::Nothing)
+    }
+
+    /// Handle a request, and encode the response into `outbuf`. Quiet pokes
+    /// that succeed don't get a response, so that can be empty.
+    pub fn handle<'a>(&mut self, header: Header, req: Request<'_>, outbuf: &'a mut [u8]) -> (&'a [u8], After) {
+        let mut membuf = [0u8; 256];
+        let mut after = After::Nothing;
+
+        let resp: Result<Response<'_>, Error> = match req {
+            Request::PeekBytes { addr, len } => {
+                if len > membuf.len() {
+                    Err(Error::RangeTooLarge { request: len, max: membuf.len() })
+                } else {
+                    self.scratch(addr, len).map(|src| {
+                        let buf = &mut membuf[..len];
+                        buf.copy_from_slice(src);
+                        Response::PeekBytes(PeekBytes { addr, val: Managed::from_borrowed(buf) })
+                    })
+                }
+            }
+            Request::PokeBytes { addr, val } => {
+                self.poke(addr, val.as_slice()).map(|()| Response::Poked(Poked { addr }))
+            }
+            Request::Bootload { addr } => {
+                let image = self.loaded(addr);
+                self.unsigned_allowed().and_then(|()| self.validate_image(addr, image)).map(|()| {
+                    magic::arm_boot(&mut self.magic, addr);
+                    magic::set_last_boot(&mut self.magic, addr, image);
+
+                    after = After::Reset;
+                    Response::Bootloading { addr }
+                })
+            }
+            Request::PeekBytesFlash { addr, len } => {
+                if len > membuf.len() {
+                    Err(Error::RangeTooLarge { request: len, max: membuf.len() })
+                } else {
+                    self.flash_slice(addr, len).map(|src| {
+                        let buf = &mut membuf[..len];
+                        buf.copy_from_slice(src);
+                        Response::PeekBytesFlash(PeekBytes { addr, val: Managed::from_borrowed(buf) })
+                    })
+                }
+            }
+            Request::ClearMagic => {
+                self.magic.as_mut_slice().fill(0);
+                Ok(Response::MagicCleared)
+            }
+            Request::Reboot { target } => {
+                let res = match target {
+                    RebootTarget::Stage0 => {
+                        magic::set(&mut self.magic, magic::STAY_IDX, magic::STAY);
+                        Ok(())
+                    }
+                    // Scratch RAM could have been poked since it was checked
+                    RebootTarget::Ram => self.unsigned_allowed().and_then(|()| match magic::last_boot(&self.magic) {
+                        Some((addr, image)) => {
+                            self.validate_image(addr, image).map(|()| magic::arm_boot(&mut self.magic, addr))
+                        }
+                        None => Err(Error::NoRamImage),
+                    }),
+                    RebootTarget::Flash { addr } => self.reboot_flash(addr),
+                };
+
+                res.map(|()| {
+                    after = After::Reset;
+                    Response::Rebooting(target)
+                })
+            }
+            Request::FlashCopy { ram_start, flash_start, len } => {
+                self.unsigned_allowed().and_then(|()| self.flash_copy(ram_start, flash_start, len))
+            }
+            Request::GetInfo => Ok(Response::Info(self.device_info())),
+            Request::Crc32 { addr, len } => {
+                self.scratch(addr, len).map(|data| Response::Crc32(Crc32 { addr, len, crc: crc32(data) }))
+            }
+            Request::Crc32Flash { addr, len } => {
+                self.flash_slice(addr, len).map(|data| Response::Crc32Flash(Crc32 { addr, len, crc: crc32(data) }))
+            }
+            Request::Sha256 { addr, len } => {
+                self.scratch(addr, len).map(|data| Response::Sha256(Sha256 { addr, len, digest: sha256(data) }))
+            }
+            Request::Sha256Flash { addr, len } => self
+                .flash_slice(addr, len)
+                .map(|data| Response::Sha256Flash(Sha256 { addr, len, digest: sha256(data) })),
+            Request::PokeBytesQuiet { addr, val } => match self.poke(addr, val.as_slice()) {
+                Ok(()) => {
+                    // No news is good news, the host finds out with the next `Sync`
+                    self.quiet_pokes += 1;
+                    return (&[], after);
+                }
+                Err(e) => Err(e),
+            },
+            Request::Sync => Ok(Response::Synced(Synced { pokes: core::mem::take(&mut self.quiet_pokes) })),
+            Request::BootloadImage { header } => {
+                self.unsigned_allowed().and_then(|()| self.boot_image(&header, &mut after))
+            }
+            Request::FlashCopyImage { ram_start, header } => {
+                self.unsigned_allowed().and_then(|()| self.flash_copy_image(ram_start, &header))
+            }
+            Request::BootloadSignedImage { header, signature } => self
+                .check_signature(&header, header.load_addr as usize, &signature)
+                .and_then(|()| self.boot_image(&header, &mut after)),
+            Request::FlashCopySignedImage { ram_start, header, signature } => self
+                .check_signature(&header, ram_start, &signature)
+                .and_then(|()| self.flash_copy_image(ram_start, &header)),
+            Request::GetSlots => Slots::load(&mut self.flash, self.config.slots)
+                .map(|slots| Response::Slots(SlotsInfo { layout: self.config.slots, state: slots.state() }))
+                .map_err(|_| Error::SlotStateFailed),
+            Request::TrialBoot { slot } => self.trial_boot(slot, &mut after),
+            Request::PokeBytesQuietLz4 { addr, len, val } => match self.inflate(addr, len, val.as_slice()) {
+                Ok(()) => {
+                    self.quiet_pokes += 1;
+                    return (&[], after);
+                }
+                Err(e) => Err(e),
+            },
+            Request::Call { addr, args } => self.unsigned_allowed().and_then(|()| self.call(addr, args)),
+        };
+
+        (encode(&FromStage0::Response(header, resp), outbuf), after)
+    }
+
+    /// Pick the flash slot to boot, and record the boot first if it's a
+    /// trial. A trial image that doesn't confirm itself is rolled back on
+    /// the next reset.
+    pub fn boot_slot(&mut self) -> Option<u32> {
+        let layout = self.config.slots;
+        let mut slots = Slots::load(&mut self.flash, layout).ok()?;
+        let slot = slots.boot().ok()?;
+        let active = layout.slot_addr(slots.state().active);
+
+        let addr = layout.slot_addr(slot);
+        if self.validate_image(addr, self.loaded(addr)).is_ok() {
+            return Some(addr);
+        }
+
+        // A trial image that doesn't even look bootable used up its chance
+        // already, so fall back to the active slot right away
+        (active != addr && self.validate_image(active, self.loaded(active)).is_ok()).then_some(active)
+    }
+
+    /// Check that the vector table at `addr` looks bootable, and that its
+    /// reset vector points into `image`, the image loaded with it. A bad
+    /// address gets an error instead of a hard fault right after the reset.
+    pub fn validate_image(&self, addr: u32, image: MemRange) -> Result<(), Error> {
+        let invalid = |reason| Err(Error::InvalidImage { reason });
+
+        if (addr & (self.config.vtor_align - 1)) != 0 {
+            return invalid(InvalidImageReason::Misaligned { addr, align: self.config.vtor_align });
+        }
+
+        // The whole image has to live in the same region as its vector table
+        let start = addr as usize;
+        let scratch = self.scratch.range();
+        let flash = self.app_flash();
+        let (region, vt) = if scratch.contains(start, 8) {
+            (scratch, &self.scratch.as_slice()[start - scratch.start..][..8])
+        } else if flash.contains(start, 8) {
+            (flash, self.flash.slice(start, 8))
+        } else {
+            return invalid(InvalidImageReason::NotInScratchOrFlash { addr });
+        };
+
+        let sp = u32::from_le_bytes([vt[0], vt[1], vt[2], vt[3]]);
+        let reset = u32::from_le_bytes([vt[4], vt[5], vt[6], vt[7]]);
+
+        // A full descending stack may start right at the end of RAM
+        let ram = self.config.ram;
+        if (sp as usize) <= ram.start || (sp as usize) > ram.end() || (sp & 0b11) != 0 {
+            return invalid(InvalidImageReason::StackPointerNotInRam { sp });
+        }
+        if (reset & 1) == 0 {
+            return invalid(InvalidImageReason::ResetVectorNotThumb { reset });
+        }
+        let code = (reset & !1) as usize;
+        if !region.contains(code, 1) || !image.contains(code, 1) {
+            return invalid(InvalidImageReason::ResetVectorOutsideImage { reset });
+        }
+
+        Ok(())
+    }
+
+    /// Arm a boot of the image in flash with its vector table at `addr`.
+    /// Anything in scratch RAM goes through `Bootload` instead. A signed
+    /// build only lets signed images into flash, so the signature was
+    /// checked on the way in.
+    fn reboot_flash(&mut self, addr: u32) -> Result<(), Error> {
+        let flash = self.app_flash();
+        if !flash.contains(addr as usize, 8) {
+            return Err(Error::AddressOutOfRange { request: addr as usize, len: 8, min: flash.start, max: flash.end() });
+        }
+        self.validate_image(addr, self.loaded(addr))?;
+        magic::arm_boot(&mut self.magic, addr);
+        Ok(())
+    }
+
+    /// The image loaded with the vector table at `addr`, as far as stage0
+    /// knows: what was poked into scratch RAM, or the flash slot it's in
+    fn loaded(&self, addr: u32) -> MemRange {
+        let start = addr as usize;
+        match self.poked {
+            Some(poked) if poked.contains(start, 1) => return poked,
+            _ => {}
+        }
+
+        let layout = self.config.slots;
+        match layout.slot_of(addr) {
+            Some(slot) => MemRange { start: layout.slot_addr(slot) as usize, len: layout.slot_size as usize },
+            None => MemRange { start, len: 0 },
+        }
+    }
+
+    pub fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            bootloader_version: self.config.version,
+            icd_version: ICD_VERSION,
+            device_id: self.board.device_id(),
+            scratch: self.scratch.range(),
+            magic: self.magic.range(),
+            flash_size: self.flash_size(),
+            flash_page_size: F::ERASE_SIZE,
+            bootloader: MemRange { start: 0, len: self.config.bootloader_size },
+        }
+    }
+
+    fn flash_size(&self) -> usize {
+        self.flash.capacity()
+    }
+
+    /// Flash after stage0, where applications go
+    fn app_flash(&self) -> MemRange {
+        MemRange { start: self.config.bootloader_size, len: self.flash_size() - self.config.bootloader_size }
+    }
+
+    /// `len` bytes of scratch RAM at `addr`
+    fn scratch(&self, addr: usize, len: usize) -> Result<&[u8], Error> {
+        let range = self.scratch.range();
+        if !range.contains(addr, len) {
+            return Err(Error::AddressOutOfRange { request: addr, len, min: range.start, max: range.end() });
+        }
+        Ok(&self.scratch.as_slice()[addr - range.start..][..len])
+    }
+
+    fn scratch_mut(&mut self, addr: usize, len: usize) -> Result<&mut [u8], Error> {
+        self.scratch(addr, len)?;
+        let start = self.scratch.start();
+        Ok(&mut self.scratch.as_mut_slice()[addr - start..][..len])
+    }
+
+    fn poke(&mut self, addr: usize, val: &[u8]) -> Result<(), Error> {
+        self.scratch_mut(addr, val.len())?.copy_from_slice(val);
+        self.mark_poked(addr, val.len());
+        Ok(())
+    }
+
+    fn mark_poked(&mut self, addr: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.poked = Some(match self.poked {
+            Some(poked) => {
+                let start = poked.start.min(addr);
+                MemRange { start, len: poked.end().max(addr + len) - start }
+            }
+            None => MemRange { start: addr, len },
+        });
+    }
+
+    /// Inflate the LZ4 block `block` into the `len` bytes of scratch RAM at `addr`
+    fn inflate(&mut self, addr: usize, len: usize, block: &[u8]) -> Result<(), Error> {
+        let out = self.scratch_mut(addr, len)?;
+        match lz4_flex::block::decompress_into(block, out) {
+            Ok(n) if n == len => {
+                self.mark_poked(addr, len);
+                Ok(())
+            }
+            _ => Err(Error::DecompressFailed),
+        }
+    }
+
+    /// `len` bytes of flash at `addr`
+    fn flash_slice(&self, addr: usize, len: usize) -> Result<&[u8], Error> {
+        let size = self.flash_size();
+        match addr.checked_add(len) {
+            Some(end) if end <= size => Ok(self.flash.slice(addr, len)),
+            _ => Err(Error::AddressOutOfRange { request: addr, len, min: 0, max: size }),
+        }
+    }
+
+    fn flash_copy(&mut self, ram_start: usize, flash_start: usize, len: usize) -> Result<Response<'static>, Error> {
+        self.scratch(ram_start, len)?;
+        let size = self.flash_size();
+        match flash_start.checked_add(len) {
+            Some(end) if end <= size => {}
+            _ => return Err(Error::AddressOutOfRange { request: flash_start, len, min: 0, max: size }),
+        }
+        if (flash_start & (F::ERASE_SIZE - 1)) != 0 {
+            return Err(Error::UnalignedFlashAddr(UnalignedFlashAddr { addr: flash_start, align: F::ERASE_SIZE }));
+        }
+        if flash_start < self.config.bootloader_size {
+            return Err(Error::CantOverwriteBootloader);
+        }
+
+        let data = &self.scratch.as_slice()[ram_start - self.scratch.start()..][..len];
+        let flash = &mut self.flash;
+        let mut idx = flash_start as u32;
+
+        let res = data.chunks(F::ERASE_SIZE).try_for_each(|ch| {
+            flash.erase(idx, idx + (F::ERASE_SIZE as u32))?;
+            let aligned_end = ch.len() & !(F::WRITE_SIZE - 1);
+            let (aligned, unaligned) = ch.split_at(aligned_end);
+            flash.write(idx, aligned)?;
+
+            if !unaligned.is_empty() {
+                // Wider than any write size we'll come across
+                let mut extra = [0xFF; 32];
+                let extra = &mut extra[..F::WRITE_SIZE];
+                extra[..unaligned.len()].copy_from_slice(unaligned);
+                flash.write(idx + (aligned.len() as u32), extra)?;
+            }
+
+            idx += ch.len() as u32;
+            Ok(())
+        });
+
+        res.map(|()| Response::FlashCopied).map_err(|_: F::Error| Error::FlashCopyFailed)
+    }
+
+    fn boot_image(&mut self, header: &ImageHeader, after: &mut After) -> Result<Response<'static>, Error> {
+        self.check_image(header, header.load_addr as usize)?;
+        let image = MemRange { start: header.load_addr as usize, len: header.image_len as usize };
+        self.validate_image(header.entry, image)?;
+
+        magic::arm_boot(&mut self.magic, header.entry);
+        magic::set_last_boot(&mut self.magic, header.entry, image);
+
+        *after = After::Reset;
+        Ok(Response::Bootloading { addr: header.entry })
+    }
+
+    fn flash_copy_image(&mut self, ram_start: usize, header: &ImageHeader) -> Result<Response<'static>, Error> {
+        let flash_start = header.load_addr as usize;
+        let len = header.image_len as usize;
+
+        self.check_image(header, ram_start)?;
+        let resp = self.flash_copy(ram_start, flash_start, len)?;
+
+        // Make sure it made it into flash intact, too
+        let written = self.flash_slice(flash_start, len)?;
+        header.check_image(written).map_err(|_| Error::FlashCopyFailed)?;
+        Ok(resp)
+    }
+
+    /// Check `header`, and the image it describes sitting at `addr` in scratch RAM
+    fn check_image(&self, header: &ImageHeader, addr: usize) -> Result<(), Error> {
+        let image = self.scratch_image(header, addr)?;
+        header.check_image(image).map_err(Error::BadImage)
+    }
+
+    /// The image `header` describes, sitting at `addr` in scratch RAM
+    fn scratch_image(&self, header: &ImageHeader, addr: usize) -> Result<&[u8], Error> {
+        header.check().map_err(Error::BadImage)?;
+        self.scratch(addr, header.image_len as usize)
+    }
+
+    /// Is anything but a signed image allowed to run or go into flash?
+    fn unsigned_allowed(&self) -> Result<(), Error> {
+        match self.config.public_key {
+            Some(_) => Err(Error::SignatureRequired),
+            None => Ok(()),
+        }
+    }
+
+    /// Without a key, there is nothing to check a signature against
+    fn check_signature(&self, header: &ImageHeader, addr: usize, signature: &Signature) -> Result<(), Error> {
+        match &self.config.public_key {
+            Some(key) => {
+                let image = self.scratch_image(header, addr)?;
+                soup_image::signature::verify(key, header, image, signature).map_err(Error::BadImage)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Put `slot` on trial, and boot it right away
+    fn trial_boot(&mut self, slot: Slot, after: &mut After) -> Result<Response<'static>, Error> {
+        let addr = self.config.slots.slot_addr(slot);
+        self.validate_image(addr, self.loaded(addr))?;
+
+        let mut slots = Slots::load(&mut self.flash, self.config.slots).map_err(|_| Error::SlotStateFailed)?;
+        slots.start_trial(slot).map_err(|e| match e {
+            SlotError::SlotIsActive => Error::SlotIsActive,
+            SlotError::Flash(_) => Error::SlotStateFailed,
+        })?;
+        // Boot it now rather than waiting for the autoboot, which a connected
+        // host would cancel
+        slots.boot().map_err(|_| Error::SlotStateFailed)?;
+
+        magic::arm_boot(&mut self.magic, addr);
+        *after = After::Reset;
+        Ok(Response::TrialBooting { slot })
+    }
+
+    /// Call the Thumb function at `addr` in scratch RAM, and hand back its `r0`
+    fn call(&mut self, addr: u32, args: [u32; 4]) -> Result<Response<'static>, Error> {
+        if (addr & 1) == 0 {
+            return Err(Error::NotThumb { addr });
+        }
+        self.scratch((addr & !1) as usize, 2)?;
+
+        let r0 = unsafe { self.board.call(addr, args) };
+        Ok(Response::Called { r0 })
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(data)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(data).into()
+}
+
+fn encode<'a>(msg: &FromStage0<'_>, outbuf: &'a mut [u8]) -> &'a [u8] {
+    match postcard::to_slice_cobs(msg, outbuf) {
+        Ok(ser) => ser,
+        Err(_) => &[0x00],
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,73 @@
+//! The magic RAM words, which survive a reset
+//!
+//! The boot command and the stay flag are consumed by the next reset, the
+//! record of the last boot sticks around for `RebootTarget::Ram`.
+
+use stage0_icd::MemRange;
+
+use crate::Memory;
+
+pub const BOOT_CMD: u32 = 0x0FACADE0;
+pub const LAST_BOOT: u32 = 0x1A57B007;
+pub const STAY: u32 = 0x57A7_57A7;
+pub const DOUBLE_TAP: u32 = 0xD00B_1E00;
+
+/// Word indices
+pub const BOOT_CMD_IDX: usize = 0;
+pub const LAST_BOOT_IDX: usize = 2;
+pub const STAY_IDX: usize = 4;
+pub const DOUBLE_TAP_IDX: usize = 5;
+/// Start and length of the image the last boot was for
+pub const LAST_BOOT_IMAGE_IDX: usize = 6;
+
+pub fn get(magic: &impl Memory, idx: usize) -> u32 {
+    let word = &magic.as_slice()[idx * 4..][..4];
+    u32::from_le_bytes([word[0], word[1], word[2], word[3]])
+}
+
+pub fn set(magic: &mut impl Memory, idx: usize, val: u32) {
+    magic.as_mut_slice()[idx * 4..][..4].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Read a word, and clear it
+pub fn take(magic: &mut impl Memory, idx: usize) -> u32 {
+    let val = get(magic, idx);
+    set(magic, idx, 0);
+    val
+}
+
+/// Have the next reset jump to the image at `addr`
+pub fn arm_boot(magic: &mut impl Memory, addr: u32) {
+    set(magic, BOOT_CMD_IDX, BOOT_CMD);
+    set(magic, BOOT_CMD_IDX + 1, addr);
+}
+
+/// The address armed by `arm_boot`, if there is one. Disarms it.
+pub fn take_boot(magic: &mut impl Memory) -> Option<u32> {
+    match (take(magic, BOOT_CMD_IDX), take(magic, BOOT_CMD_IDX + 1)) {
+        (BOOT_CMD, addr) => Some(addr),
+        _ => None,
+    }
+}
+
+/// Record booting the vector table at `addr`, of the image in `image`
+pub fn set_last_boot(magic: &mut impl Memory, addr: u32, image: MemRange) {
+    set(magic, LAST_BOOT_IDX, LAST_BOOT);
+    set(magic, LAST_BOOT_IDX + 1, addr);
+    set(magic, LAST_BOOT_IMAGE_IDX, image.start as u32);
+    set(magic, LAST_BOOT_IMAGE_IDX + 1, image.len as u32);
+}
+
+/// The vector table and image recorded by `set_last_boot`
+pub fn last_boot(magic: &impl Memory) -> Option<(u32, MemRange)> {
+    match get(magic, LAST_BOOT_IDX) {
+        LAST_BOOT => {
+            let image = MemRange {
+                start: get(magic, LAST_BOOT_IMAGE_IDX) as usize,
+                len: get(magic, LAST_BOOT_IMAGE_IDX + 1) as usize,
+            };
+            Some((get(magic, LAST_BOOT_IDX + 1), image))
+        }
+        _ => None,
+    }
+}
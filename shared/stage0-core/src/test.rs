@@ -0,0 +1,558 @@
+use super::*;
+use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+use frame::Frames;
+use soup_image::AppVersion;
+
+const SCRATCH: usize = 0x2000_0000;
+const SCRATCH_SIZE: usize = 0x1_0000;
+const MAGIC: usize = 0x2003_FFC0;
+const FLASH_SIZE: usize = 1024 * 1024;
+const BOOTLOADER_SIZE: usize = 0x8000;
+const ACC_SIZE: usize = 512;
+
+struct Ram {
+    start: usize,
+    mem: Vec<u8>,
+}
+
+impl Memory for Ram {
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.mem
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+}
+
+/// Flash that behaves like NOR: writes only clear bits
+struct MockFlash {
+    mem: Vec<u8>,
+}
+
+impl ErrorType for MockFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(self.mem.get(offset..offset + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.mem.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.mem.get_mut(from as usize..to as usize).ok_or(NorFlashErrorKind::OutOfBounds)?.fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(Self::WRITE_SIZE) || !bytes.len().is_multiple_of(Self::WRITE_SIZE) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        let mem = self.mem.get_mut(offset..offset + bytes.len()).ok_or(NorFlashErrorKind::OutOfBounds)?;
+        mem.iter_mut().zip(bytes).for_each(|(m, b)| *m &= b);
+        Ok(())
+    }
+}
+
+impl Flash for MockFlash {
+    fn slice(&self, addr: usize, len: usize) -> &[u8] {
+        &self.mem[addr..][..len]
+    }
+}
+
+#[derive(Default)]
+struct MockBoard {
+    calls: Vec<(u32, [u32; 4])>,
+}
+
+impl Board for MockBoard {
+    fn device_id(&self) -> u64 {
+        0x0102_0304_0506_0708
+    }
+
+    unsafe fn call(&mut self, addr: u32, args: [u32; 4]) -> u32 {
+        self.calls.push((addr, args));
+        args.iter().sum()
+    }
+}
+
+type TestStage0 = Stage0<Ram, Ram, MockFlash, MockBoard>;
+
+const CONFIG: Config = Config {
+    version: Version { major: 2, minor: 0, patch: 0 },
+    ram: MemRange { start: 0x2000_0000, len: 0x4_0000 },
+    bootloader_size: BOOTLOADER_SIZE,
+    vtor_align: 256,
+    slots: soup_slots::NRF52840,
+    public_key: None,
+};
+
+fn stage0() -> TestStage0 {
+    Stage0::new(
+        Ram { start: SCRATCH, mem: vec![0; SCRATCH_SIZE] },
+        Ram { start: MAGIC, mem: vec![0; 64] },
+        MockFlash { mem: vec![0xFF; FLASH_SIZE] },
+        MockBoard::default(),
+        CONFIG,
+    )
+}
+
+/// Push `bytes` through the frame accumulator, and collect the responses
+fn feed(s0: &mut TestStage0, frames: &mut Frames<ACC_SIZE>, bytes: &[u8]) -> Vec<(Vec<u8>, After)> {
+    let mut outbuf = [0u8; 512];
+    let mut out = Vec::new();
+    for &byte in bytes {
+        if let Some((raw, broken)) = frames.push(byte) {
+            let (resp, after) = s0.handle_frame(raw, broken, &mut outbuf);
+            if !resp.is_empty() {
+                out.push((resp.to_vec(), after));
+            }
+        }
+    }
+    out
+}
+
+/// Send `req` as a frame, and decode the one response
+fn request_after(s0: &mut TestStage0, req: Request<'_>) -> (Result<Response<'static>, Error>, After) {
+    let hdr = Header { seq: 7 };
+    let frame = postcard::to_stdvec_cobs(&ToStage0::Request(hdr, req)).unwrap();
+    let mut out = feed(s0, &mut Frames::new(), &frame);
+    assert_eq!(out.len(), 1);
+
+    let (mut resp, after) = out.remove(0);
+    match postcard::from_bytes_cobs::<FromStage0<'_>>(&mut resp).unwrap() {
+        FromStage0::Response(h, resp) => {
+            assert_eq!(h, hdr);
+            (resp.map(|r| r.to_owned()), after)
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+fn request(s0: &mut TestStage0, req: Request<'_>) -> Result<Response<'static>, Error> {
+    request_after(s0, req).0
+}
+
+/// A minimal vector table for an image at `addr`, padded to `len` bytes
+fn vector_table(addr: u32, len: usize) -> Vec<u8> {
+    let mut image = vec![0u8; len];
+    image[0..4].copy_from_slice(&0x2004_0000u32.to_le_bytes());
+    image[4..8].copy_from_slice(&(addr + 0x101).to_le_bytes());
+    image
+}
+
+fn poke(s0: &mut TestStage0, addr: usize, data: &[u8]) {
+    s0.scratch.mem[addr - SCRATCH..][..data.len()].copy_from_slice(data);
+    s0.mark_poked(addr, data.len());
+}
+
+#[test]
+fn peek_poke() {
+    let mut s0 = stage0();
+    let val = Managed::from_borrowed(&[1, 2, 3]);
+    assert!(matches!(
+        request(&mut s0, Request::PokeBytes { addr: SCRATCH + 0x10, val }),
+        Ok(Response::Poked(Poked { addr })) if addr == SCRATCH + 0x10
+    ));
+
+    match request(&mut s0, Request::PeekBytes { addr: SCRATCH + 0x0F, len: 5 }) {
+        Ok(Response::PeekBytes(pb)) => assert_eq!(pb.val.as_slice(), &[0, 1, 2, 3, 0]),
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+#[test]
+fn scratch_bounds() {
+    let mut s0 = stage0();
+    let end = SCRATCH + SCRATCH_SIZE;
+
+    let out_of_range = |r: Result<Response<'static>, Error>, request: usize, len: usize| {
+        assert!(
+            matches!(r, Err(Error::AddressOutOfRange { request: r, len: l, min: SCRATCH, max }) if r == request && l == len && max == end),
+            "{r:?}"
+        );
+    };
+
+    out_of_range(request(&mut s0, Request::PeekBytes { addr: end - 2, len: 4 }), end - 2, 4);
+    out_of_range(request(&mut s0, Request::PeekBytes { addr: SCRATCH - 1, len: 1 }), SCRATCH - 1, 1);
+    out_of_range(request(&mut s0, Request::PokeBytes { addr: end, val: Managed::from_borrowed(&[1]) }), end, 1);
+    out_of_range(request(&mut s0, Request::Crc32 { addr: usize::MAX, len: 2 }), usize::MAX, 2);
+
+    // Right up to the end is fine
+    assert!(request(&mut s0, Request::PokeBytes { addr: end - 1, val: Managed::from_borrowed(&[1]) }).is_ok());
+
+    assert!(matches!(
+        request(&mut s0, Request::PeekBytes { addr: SCRATCH, len: 257 }),
+        Err(Error::RangeTooLarge { request: 257, max: 256 })
+    ));
+}
+
+#[test]
+fn flash_bounds() {
+    let mut s0 = stage0();
+
+    assert!(matches!(
+        request(&mut s0, Request::PeekBytesFlash { addr: FLASH_SIZE - 1, len: 2 }),
+        Err(Error::AddressOutOfRange { min: 0, max: FLASH_SIZE, .. })
+    ));
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopy { ram_start: SCRATCH, flash_start: 0, len: 16 }),
+        Err(Error::CantOverwriteBootloader)
+    ));
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopy { ram_start: SCRATCH, flash_start: BOOTLOADER_SIZE + 4, len: 16 }),
+        Err(Error::UnalignedFlashAddr(UnalignedFlashAddr { addr, align: 4096 })) if addr == BOOTLOADER_SIZE + 4
+    ));
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopy { ram_start: SCRATCH + SCRATCH_SIZE - 8, flash_start: BOOTLOADER_SIZE, len: 16 }),
+        Err(Error::AddressOutOfRange { .. })
+    ));
+    // Past the end, wrapping around, or only in range once cut down to 32 bits
+    for flash_start in [FLASH_SIZE, FLASH_SIZE - 0x1000, 0xFFFF_F000, usize::MAX & !0xFFF, 0x1_0000_8000] {
+        assert!(
+            matches!(
+                request(&mut s0, Request::FlashCopy { ram_start: SCRATCH, flash_start, len: 0x1001 }),
+                Err(Error::AddressOutOfRange { request, min: 0, max: FLASH_SIZE, .. }) if request == flash_start
+            ),
+            "0x{flash_start:X}"
+        );
+    }
+
+    // Nothing made it into flash
+    assert!(s0.flash.mem.iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn flash_copy() {
+    let mut s0 = stage0();
+    s0.flash.mem[0x9000..0x9010].fill(0x00);
+
+    // Longer than a page, and not a multiple of the write size
+    let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+    poke(&mut s0, SCRATCH, &data);
+
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopy { ram_start: SCRATCH, flash_start: 0x8000, len: data.len() }),
+        Ok(Response::FlashCopied)
+    ));
+    assert_eq!(&s0.flash.mem[0x8000..][..data.len()], &data[..]);
+    assert!(s0.flash.mem[0x8000 + data.len()..0xA000].iter().all(|b| *b == 0xFF));
+
+    match request(&mut s0, Request::Crc32Flash { addr: 0x8000, len: data.len() }) {
+        Ok(Response::Crc32Flash(crc)) => assert_eq!(crc.crc, crc32(&data)),
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+#[test]
+fn validate() {
+    let mut s0 = stage0();
+    let reason = |s0: &TestStage0, addr| match s0.validate_image(addr, s0.loaded(addr)) {
+        Err(Error::InvalidImage { reason }) => Some(reason),
+        Ok(()) => None,
+        other => panic!("unexpected {other:?}"),
+    };
+
+    let addr = SCRATCH as u32 + 0x100;
+    poke(&mut s0, addr as usize, &vector_table(addr, 0x200));
+    assert_eq!(reason(&s0, addr), None);
+
+    assert_eq!(reason(&s0, addr + 4), Some(InvalidImageReason::Misaligned { addr: addr + 4, align: 256 }));
+    assert_eq!(reason(&s0, 0x100), Some(InvalidImageReason::NotInScratchOrFlash { addr: 0x100 }));
+    assert_eq!(reason(&s0, addr + 0x100), Some(InvalidImageReason::StackPointerNotInRam { sp: 0 }));
+
+    poke(&mut s0, addr as usize + 4, &(addr + 0x100).to_le_bytes());
+    assert_eq!(reason(&s0, addr), Some(InvalidImageReason::ResetVectorNotThumb { reset: addr + 0x100 }));
+
+    poke(&mut s0, addr as usize + 4, &0x8001u32.to_le_bytes());
+    assert_eq!(reason(&s0, addr), Some(InvalidImageReason::ResetVectorOutsideImage { reset: 0x8001 }));
+
+    // Still in scratch RAM, but past anything that was loaded
+    let stale = addr + 0x1001;
+    poke(&mut s0, addr as usize + 4, &stale.to_le_bytes());
+    assert_eq!(reason(&s0, addr), Some(InvalidImageReason::ResetVectorOutsideImage { reset: stale }));
+    poke(&mut s0, addr as usize + 4, &(addr + 0x101).to_le_bytes());
+
+    // A vector table that was never loaded, just left over in RAM
+    let mut fresh = stage0();
+    fresh.scratch.mem[..0x200].copy_from_slice(&vector_table(SCRATCH as u32, 0x200));
+    assert_eq!(
+        reason(&fresh, SCRATCH as u32),
+        Some(InvalidImageReason::ResetVectorOutsideImage { reset: SCRATCH as u32 + 0x101 })
+    );
+
+    // The same goes for flash
+    s0.flash.mem[0x8000..][..0x200].copy_from_slice(&vector_table(0x8000, 0x200));
+    assert_eq!(reason(&s0, 0x8000), None);
+}
+
+#[test]
+fn bootload() {
+    let mut s0 = stage0();
+    let addr = SCRATCH as u32;
+    poke(&mut s0, SCRATCH, &vector_table(addr, 0x200));
+
+    let (resp, after) = request_after(&mut s0, Request::Bootload { addr });
+    assert!(matches!(resp, Ok(Response::Bootloading { addr: a }) if a == addr));
+    assert_eq!(after, After::Reset);
+    assert_eq!(magic::take_boot(&mut s0.magic), Some(addr));
+    assert_eq!(magic::last_boot(&s0.magic), Some((addr, MemRange { start: SCRATCH, len: 0x200 })));
+
+    // Again, from the record of the last boot
+    let target = RebootTarget::Ram;
+    assert!(matches!(request(&mut s0, Request::Reboot { target }), Ok(Response::Rebooting(RebootTarget::Ram))));
+    assert_eq!(magic::take_boot(&mut s0.magic), Some(addr));
+
+    assert!(matches!(request(&mut s0, Request::ClearMagic), Ok(Response::MagicCleared)));
+    assert!(matches!(request(&mut s0, Request::Reboot { target }), Err(Error::NoRamImage)));
+
+    // Only images in flash boot through `RebootTarget::Flash`
+    let target = RebootTarget::Flash { addr };
+    assert!(matches!(
+        request(&mut s0, Request::Reboot { target }),
+        Err(Error::AddressOutOfRange { request, min: BOOTLOADER_SIZE, max: FLASH_SIZE, .. }) if request == SCRATCH
+    ));
+    assert_eq!(magic::take_boot(&mut s0.magic), None);
+
+    s0.flash.mem[0x8000..][..0x200].copy_from_slice(&vector_table(0x8000, 0x200));
+    let target = RebootTarget::Flash { addr: 0x8000 };
+    assert!(matches!(request(&mut s0, Request::Reboot { target }), Ok(Response::Rebooting(_))));
+    assert_eq!(magic::take_boot(&mut s0.magic), Some(0x8000));
+}
+
+#[test]
+fn images() {
+    let mut s0 = stage0();
+    let addr = SCRATCH as u32;
+    let image = vector_table(addr, 0x200);
+    let header = ImageHeader::new(addr, addr, AppVersion { major: 1, minor: 0, patch: 0 }, &image);
+    poke(&mut s0, SCRATCH, &image);
+
+    let (resp, after) = request_after(&mut s0, Request::BootloadImage { header });
+    assert!(matches!(resp, Ok(Response::Bootloading { .. })));
+    assert_eq!(after, After::Reset);
+
+    // Into flash
+    let flashed = ImageHeader::new(0x8000, 0x8000, header.app_version, &image);
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopyImage { ram_start: SCRATCH, header: flashed }),
+        Ok(Response::FlashCopied)
+    ));
+    assert_eq!(&s0.flash.mem[0x8000..][..image.len()], &image[..]);
+
+    // Corrupted after the header was made
+    poke(&mut s0, SCRATCH + 0x100, &[0x55]);
+    let (resp, after) = request_after(&mut s0, Request::BootloadImage { header });
+    assert!(matches!(resp, Err(Error::BadImage(soup_image::HeaderError::ImageCrcMismatch))));
+    assert_eq!(after, After::Nothing);
+
+    // The reset vector has to be in the image the header covers, not just
+    // somewhere in what was poked
+    let mut short = vector_table(addr, 0x200);
+    short[4..8].copy_from_slice(&(addr + 0x181).to_le_bytes());
+    poke(&mut s0, SCRATCH, &short);
+    let header = ImageHeader::new(addr, addr, header.app_version, &short[..0x100]);
+    assert!(matches!(
+        request(&mut s0, Request::BootloadImage { header }),
+        Err(Error::InvalidImage { reason: InvalidImageReason::ResetVectorOutsideImage { .. } })
+    ));
+}
+
+#[test]
+fn signed_images() {
+    let seed = [0x42; 32];
+    let mut s0 = stage0();
+    s0.config.public_key = Some(soup_image::signature::public_key(&seed));
+
+    let addr = SCRATCH as u32;
+    let image = vector_table(addr, 0x200);
+    let header = ImageHeader::new(addr, addr, AppVersion { major: 1, minor: 0, patch: 0 }, &image);
+    poke(&mut s0, SCRATCH, &image);
+
+    // Nothing unsigned runs, or goes into flash
+    assert!(matches!(request(&mut s0, Request::Bootload { addr }), Err(Error::SignatureRequired)));
+    assert!(matches!(request(&mut s0, Request::BootloadImage { header }), Err(Error::SignatureRequired)));
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopy { ram_start: SCRATCH, flash_start: 0x8000, len: 4 }),
+        Err(Error::SignatureRequired)
+    ));
+    assert!(matches!(request(&mut s0, Request::Call { addr: addr | 1, args: [0; 4] }), Err(Error::SignatureRequired)));
+    let target = RebootTarget::Flash { addr };
+    assert!(matches!(request(&mut s0, Request::Reboot { target }), Err(Error::AddressOutOfRange { .. })));
+    assert_eq!(magic::take_boot(&mut s0.magic), None);
+
+    let signature = soup_image::signature::sign(&seed, &header, &image);
+    assert!(matches!(
+        request(&mut s0, Request::BootloadSignedImage { header, signature }),
+        Ok(Response::Bootloading { .. })
+    ));
+
+    let mut bad = signature;
+    bad.r[0] ^= 1;
+    assert!(matches!(
+        request(&mut s0, Request::BootloadSignedImage { header, signature: bad }),
+        Err(Error::BadImage(soup_image::HeaderError::BadSignature))
+    ));
+
+    // Flash only boots what made it in with a signature
+    let target = RebootTarget::Flash { addr: 0x8000 };
+    assert!(matches!(request(&mut s0, Request::Reboot { target }), Err(Error::InvalidImage { .. })));
+    let image = vector_table(0x8000, 0x200);
+    let header = ImageHeader::new(0x8000, 0x8000, AppVersion { major: 1, minor: 0, patch: 0 }, &image);
+    let signature = soup_image::signature::sign(&seed, &header, &image);
+    poke(&mut s0, SCRATCH, &image);
+    assert!(matches!(
+        request(&mut s0, Request::FlashCopySignedImage { ram_start: SCRATCH, header, signature }),
+        Ok(Response::FlashCopied)
+    ));
+    assert!(matches!(request(&mut s0, Request::Reboot { target }), Ok(Response::Rebooting(_))));
+    assert_eq!(magic::take_boot(&mut s0.magic), Some(0x8000));
+}
+
+#[test]
+fn quiet_pokes() {
+    let mut s0 = stage0();
+    let mut frames = Frames::new();
+    let frame = |seq, req| postcard::to_stdvec_cobs(&ToStage0::Request(Header { seq }, req)).unwrap();
+
+    let data = [0xAB; 300];
+    let lz4 = lz4_flex::block::compress(&data);
+    let mut bytes = frame(0, Request::PokeBytesQuiet { addr: SCRATCH, val: Managed::from_borrowed(&[1, 2]) });
+    bytes.extend(frame(1, Request::PokeBytesQuietLz4 { addr: SCRATCH + 2, len: 300, val: Managed::from_borrowed(&lz4) }));
+    // Doesn't inflate to its length
+    bytes.extend(frame(2, Request::PokeBytesQuietLz4 { addr: SCRATCH + 0x1000, len: 301, val: Managed::from_borrowed(&lz4) }));
+    bytes.extend(frame(3, Request::Sync));
+
+    let mut out = feed(&mut s0, &mut frames, &bytes);
+    let mut decode = |i: usize| match postcard::from_bytes_cobs::<FromStage0<'_>>(&mut out[i].0).unwrap() {
+        FromStage0::Response(h, r) => (h.seq, r.map(|r| r.to_owned())),
+        other => panic!("unexpected {other:?}"),
+    };
+
+    assert!(matches!(decode(0), (2, Err(Error::DecompressFailed))));
+    assert!(matches!(decode(1), (3, Ok(Response::Synced(Synced { pokes: 2 })))));
+    assert_eq!(out.len(), 2);
+    assert_eq!(&s0.scratch.mem[..2], &[1, 2]);
+    assert_eq!(&s0.scratch.mem[2..302], &data[..]);
+}
+
+/// Feed in one frame that can't be handled, and decode the error it gets
+fn rejected(s0: &mut TestStage0, frames: &mut Frames<ACC_SIZE>, bytes: &[u8]) -> (Option<Header>, Error) {
+    let mut out = feed(s0, frames, bytes);
+    assert_eq!(out.len(), 1, "{bytes:02X?}");
+    let (mut resp, after) = out.remove(0);
+    assert_eq!(after, After::Nothing);
+    match postcard::from_bytes_cobs::<FromStage0<'_>>(&mut resp).unwrap() {
+        FromStage0::Response(h, Err(e)) => (Some(h), e),
+        FromStage0::FrameError(e) => (None, e),
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+#[test]
+fn frames() {
+    let mut s0 = stage0();
+    let mut frames = Frames::new();
+    let hdr = Header { seq: 0x1234 };
+
+    // Too large, but the header still made it
+    let big = [0x55; 600];
+    let frame = postcard::to_stdvec_cobs(&ToStage0::Request(hdr, Request::PokeBytes { addr: 0, val: Managed::from_borrowed(&big) })).unwrap();
+    assert!(matches!(rejected(&mut s0, &mut frames, &frame), (Some(h), Error::FrameTooLarge { max: ACC_SIZE }) if h == hdr));
+
+    // From a newer host
+    assert!(matches!(rejected(&mut s0, &mut frames, &[0x06, 0x01, 0xB4, 0x24, 0x80, 0x02, 0x00]), (Some(h), Error::UnknownRequest { variant: 0x100 }) if h == hdr));
+
+    // Broken after the variant
+    assert!(matches!(rejected(&mut s0, &mut frames, &[0x05, 0x01, 0xB4, 0x24, 0x01, 0x00]), (Some(h), Error::Malformed) if h == hdr));
+
+    // Nothing readable at all
+    assert!(matches!(rejected(&mut s0, &mut frames, &[0x02, 0x07, 0x00]), (None, Error::Malformed)));
+
+    // A packet went missing
+    frames.lost();
+    let frame = postcard::to_stdvec_cobs(&ToStage0::Request(hdr, Request::Sync)).unwrap();
+    assert!(matches!(rejected(&mut s0, &mut frames, &frame), (Some(h), Error::Malformed) if h == hdr));
+
+    // Stray zeroes are ignored, and everything still works afterwards
+    assert!(feed(&mut s0, &mut frames, &[0x00, 0x00]).is_empty());
+    let frame = postcard::to_stdvec_cobs(&ToStage0::Hello(Hello::CURRENT)).unwrap();
+    let mut out = feed(&mut s0, &mut frames, &frame);
+    assert!(matches!(
+        postcard::from_bytes_cobs::<FromStage0<'_>>(&mut out[0].0),
+        Ok(FromStage0::Hello(h)) if h == Hello::CURRENT
+    ));
+    assert!(matches!(request(&mut s0, Request::Sync), Ok(Response::Synced(_))));
+}
+
+#[test]
+fn call() {
+    let mut s0 = stage0();
+    let addr = SCRATCH as u32 + 0x41;
+
+    assert!(matches!(request(&mut s0, Request::Call { addr, args: [1, 2, 3, 4] }), Ok(Response::Called { r0: 10 })));
+    assert!(matches!(request(&mut s0, Request::Call { addr: addr - 1, args: [0; 4] }), Err(Error::NotThumb { .. })));
+    assert!(matches!(request(&mut s0, Request::Call { addr: 0x8001, args: [0; 4] }), Err(Error::AddressOutOfRange { .. })));
+    assert_eq!(s0.board.calls, vec![(addr, [1, 2, 3, 4])]);
+}
+
+#[test]
+fn slots() {
+    let mut s0 = stage0();
+    let layout = soup_slots::NRF52840;
+
+    // Nothing bootable anywhere
+    assert_eq!(s0.boot_slot(), None);
+
+    for addr in [layout.slot_a, layout.slot_b] {
+        s0.flash.mem[addr as usize..][..0x200].copy_from_slice(&vector_table(addr, 0x200));
+    }
+    assert_eq!(s0.boot_slot(), Some(layout.slot_a));
+
+    assert!(matches!(request(&mut s0, Request::TrialBoot { slot: Slot::A }), Err(Error::SlotIsActive)));
+    let (resp, after) = request_after(&mut s0, Request::TrialBoot { slot: Slot::B });
+    assert!(matches!(resp, Ok(Response::TrialBooting { slot: Slot::B })));
+    assert_eq!(after, After::Reset);
+    assert_eq!(magic::take_boot(&mut s0.magic), Some(layout.slot_b));
+
+    // It never confirmed itself
+    assert_eq!(s0.boot_slot(), Some(layout.slot_a));
+    match request(&mut s0, Request::GetSlots) {
+        Ok(Response::Slots(slots)) => assert_eq!(slots.state, soup_slots::State::DEFAULT),
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+#[test]
+fn info() {
+    let mut s0 = stage0();
+    match request(&mut s0, Request::GetInfo) {
+        Ok(Response::Info(info)) => {
+            assert_eq!(info.device_id, 0x0102_0304_0506_0708);
+            assert_eq!(info.scratch, MemRange { start: SCRATCH, len: SCRATCH_SIZE });
+            assert_eq!(info.magic, MemRange { start: MAGIC, len: 64 });
+            assert_eq!(info.flash_size, FLASH_SIZE);
+            assert_eq!(info.flash_page_size, 4096);
+            assert_eq!(info.bootloader, MemRange { start: 0, len: BOOTLOADER_SIZE });
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+}
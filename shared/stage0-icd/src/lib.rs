@@ -1,8 +1,73 @@
 #![cfg_attr(not(any(test, feature = "use-std")), no_std)]
 
 pub use soup_managed::Managed;
+use soup_env::parse_decimal;
+pub use soup_image::{HeaderError, ImageHeader, Signature};
+pub use soup_slots::{Layout as SlotLayout, Slot, State as SlotState, Trial};
 use serde::{Deserialize, Serialize};
 
+/// The version of this ICD crate, exchanged in [`Hello`] and reported by
+/// stage0 in [`DeviceInfo`].
+pub const ICD_VERSION: Version = Version::from_pkg(
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    env!("CARGO_PKG_VERSION_MINOR"),
+    env!("CARGO_PKG_VERSION_PATCH"),
+);
+
+/// Identifies the stage0 protocol in a [`Hello`], "S0LD"
+pub const PROTOCOL: u32 = u32::from_le_bytes(*b"S0LD");
+
+/// Everything the host sends to stage0.
+///
+/// `Hello` must stay the first variant, and [`Hello`] must never change
+/// shape, so that any two versions of the protocol can at least figure
+/// out that they don't match.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum ToStage0<'a> {
+    Hello(Hello),
+    Request(Header, #[serde(borrow)] Request<'a>),
+}
+
+/// Everything stage0 sends to the host. See [`ToStage0`] for the rules on `Hello`.
+///
+/// Every `Response`, including errors, echoes the [`Header`] of the request
+/// it answers.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum FromStage0<'a> {
+    Hello(Hello),
+    Response(Header, #[serde(borrow)] Result<Response<'a>, Error>),
+    /// A frame stage0 couldn't even read the [`Header`] of, since 5.6.0
+    FrameError(Error),
+}
+
+/// Sent with every request, and echoed back with its response.
+///
+/// The host picks the sequence numbers, and should increment them (wrapping)
+/// for every request, so it can match up responses and notice lost frames.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Header {
+    pub seq: u16,
+}
+
+/// Sent by the host when it connects, and answered by stage0 with its own.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Hello {
+    /// Always [`PROTOCOL`] for stage0
+    pub protocol: u32,
+    pub version: Version,
+}
+
+impl Hello {
+    pub const CURRENT: Self = Self {
+        protocol: PROTOCOL,
+        version: ICD_VERSION,
+    };
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum Request<'a> {
@@ -20,8 +85,13 @@ pub enum Request<'a> {
     },
 
     // Other
+    /// Forget the pending boot command, and the last bootloaded image
     ClearMagic,
-    Reboot,
+    /// Answered with `Rebooting` right before stage0 resets
+    Reboot {
+        target: RebootTarget,
+    },
+    /// Answered with `Bootloading` right before stage0 resets into the app
     Bootload {
         addr: u32,
     },
@@ -35,7 +105,168 @@ pub enum Request<'a> {
         ram_start: usize,
         flash_start: usize,
         len: usize,
+    },
+
+    // Info
+    GetInfo,
+
+    // Checksums, since 3.1.0
+    Crc32 {
+        addr: usize,
+        len: usize,
+    },
+    Crc32Flash {
+        addr: usize,
+        len: usize,
+    },
+    Sha256 {
+        addr: usize,
+        len: usize,
+    },
+    Sha256Flash {
+        addr: usize,
+        len: usize,
+    },
+
+    // Windowed uploads, since 4.1.0
+    /// Like `PokeBytes`, but only answered if it fails
+    PokeBytesQuiet {
+        addr: usize,
+        #[serde(borrow)]
+        val: Managed<'a>,
+    },
+    /// Answered with `Synced`, once every request sent before it is handled
+    Sync,
+
+    // Headered images, since 5.2.0
+    /// Check the image already poked to `header.load_addr` against `header`,
+    /// and `Bootload` its `entry` if it's intact
+    BootloadImage {
+        header: ImageHeader,
+    },
+    /// Check the image poked to `ram_start` against `header`, and copy it to
+    /// `header.load_addr` in flash if it's intact
+    FlashCopyImage {
+        ram_start: usize,
+        header: ImageHeader,
+    },
+
+    // Signed images, since 5.3.0. A stage0 built with signature checking
+    // refuses everything else that would run code, or write it to flash.
+    /// `BootloadImage`, if `signature` checks out
+    BootloadSignedImage {
+        header: ImageHeader,
+        signature: Signature,
+    },
+    /// `FlashCopyImage`, if `signature` checks out
+    FlashCopySignedImage {
+        ram_start: usize,
+        header: ImageHeader,
+        signature: Signature,
+    },
+
+    // A/B slots, since 5.4.0
+    /// Answered with `Slots`
+    GetSlots,
+    /// Put the image already in flash in `slot` on trial, and boot it.
+    /// Answered with `TrialBooting` right before stage0 resets into it.
+    TrialBoot {
+        slot: Slot,
+    },
+
+    // Compressed uploads, since 5.5.0
+    /// Like `PokeBytesQuiet`, but `val` is an LZ4 block that inflates to
+    /// `len` bytes at `addr`
+    PokeBytesQuietLz4 {
+        addr: usize,
+        len: usize,
+        #[serde(borrow)]
+        val: Managed<'a>,
+    },
+
+    // Calls, since 5.7.0
+    /// Call the Thumb function at `addr` in scratch RAM with `args` in
+    /// `r0`..`r3`, without a reset. Answered with `Called` once it returns.
+    Call {
+        addr: u32,
+        args: [u32; 4],
+    },
+}
+
+impl Request<'_> {
+    /// How many variants `Request` has. A frame with a variant at or past
+    /// this comes from a newer host.
+    pub const VARIANTS: u32 = 22;
+}
+
+/// Read the [`Header`] and the `Request` variant from the start of a
+/// COBS-encoded `ToStage0::Request` frame, without its terminating zero.
+///
+/// Works even if the rest of the frame is cut off or broken, so stage0 can
+/// tell the host which request it couldn't handle.
+pub fn peek_request(raw: &[u8]) -> Option<(Header, u32)> {
+    // Enough for the `ToStage0` tag, and the varints of `seq` and the variant
+    let mut prefix = [0u8; 9];
+    let mut len = 0;
+
+    // Each COBS code byte is followed by `code - 1` data bytes, and stands
+    // for a zero after them, unless it's 0xFF
+    let mut raw = raw.iter().copied();
+    'decode: while len < prefix.len() {
+        let code = match raw.next() {
+            Some(0) | None => break,
+            Some(code) => code,
+        };
+        for _ in 1..code {
+            match (raw.next(), prefix.get_mut(len)) {
+                (Some(byte), Some(p)) => *p = byte,
+                _ => break 'decode,
+            }
+            len += 1;
+        }
+        if code != 0xFF && len < prefix.len() {
+            len += 1;
+        }
+    }
+
+    let mut rest = &prefix[..len];
+    let tag = varint(&mut rest)?;
+    let seq = varint(&mut rest)?;
+    let variant = varint(&mut rest)?;
+    match (tag, u16::try_from(seq)) {
+        (1, Ok(seq)) => Some((Header { seq }, variant)),
+        _ => None,
+    }
+}
+
+/// Take a postcard varint off the front of `bytes`
+fn varint(bytes: &mut &[u8]) -> Option<u32> {
+    let mut val = 0u32;
+    for i in 0..5 {
+        let (byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        val |= u32::from(byte & 0x7F).checked_shl(7 * i)?;
+        if byte & 0x80 == 0 {
+            return Some(val);
+        }
     }
+    None
+}
+
+/// What to run after a `Reboot`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum RebootTarget {
+    /// Come back up in stage0, and stay there instead of booting the
+    /// flash application
+    Stage0,
+    /// The image last started with `Bootload`, which is still in scratch RAM
+    /// as long as it didn't overwrite itself
+    Ram,
+    /// An image in flash, with its vector table at `addr`
+    Flash {
+        addr: u32,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,6 +285,107 @@ pub enum Error {
     UnalignedFlashAddr(UnalignedFlashAddr),
     CantOverwriteBootloader,
     FlashCopyFailed,
+    /// `RebootTarget::Ram`, but nothing was bootloaded since the magic was cleared
+    NoRamImage,
+    /// There's nothing bootable at the requested address, since 5.1.0
+    InvalidImage {
+        reason: InvalidImageReason,
+    },
+    /// The image header is broken, or the image doesn't match it, since 5.2.0
+    BadImage(HeaderError),
+    /// This stage0 only runs signed images, since 5.3.0
+    SignatureRequired,
+    /// Only the inactive slot can be put on trial, since 5.4.0
+    SlotIsActive,
+    /// Reading or writing the slot state in flash failed, since 5.4.0
+    SlotStateFailed,
+    /// A compressed poke is corrupted, or doesn't inflate to its length, since 5.5.0
+    DecompressFailed,
+    /// The frame didn't fit stage0's frame buffer, since 5.6.0
+    FrameTooLarge {
+        max: usize,
+    },
+    /// The frame didn't decode, or parts of it got lost, since 5.6.0
+    Malformed,
+    /// The frame holds a request from a newer protocol version, since 5.6.0
+    UnknownRequest {
+        variant: u32,
+    },
+    /// A `Call` address without the Thumb bit set, since 5.7.0
+    NotThumb {
+        addr: u32,
+    },
+}
+
+/// Why stage0 refused to boot an image
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub enum InvalidImageReason {
+    /// The vector table isn't aligned the way VTOR needs it
+    Misaligned { addr: u32, align: u32 },
+    /// The vector table is neither in scratch RAM, nor in flash after stage0
+    NotInScratchOrFlash { addr: u32 },
+    /// The initial stack pointer isn't a word aligned address in RAM
+    StackPointerNotInRam { sp: u32 },
+    /// The reset vector doesn't have the Thumb bit set
+    ResetVectorNotThumb { reset: u32 },
+    /// The reset vector points outside of the image loaded with the vector
+    /// table
+    ResetVectorOutsideImage { reset: u32 },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::AddressOutOfRange { request, len, min, max } => write!(
+                f,
+                "{len} bytes at 0x{request:08X} are outside of 0x{min:08X}..0x{max:08X}"
+            ),
+            Error::RangeTooLarge { request, max } => {
+                write!(f, "{request} bytes is more than the maximum of {max}")
+            }
+            Error::UnalignedFlashAddr(UnalignedFlashAddr { addr, align }) => {
+                write!(f, "flash address 0x{addr:08X} isn't aligned to {align} bytes")
+            }
+            Error::CantOverwriteBootloader => f.write_str("refusing to overwrite the bootloader"),
+            Error::FlashCopyFailed => f.write_str("writing to flash failed"),
+            Error::NoRamImage => f.write_str("nothing was bootloaded since the magic was cleared"),
+            Error::InvalidImage { reason } => write!(f, "invalid image: {reason}"),
+            Error::BadImage(err) => write!(f, "bad image: {err}"),
+            Error::SignatureRequired => f.write_str("this stage0 only accepts signed images"),
+            Error::SlotIsActive => f.write_str("the active slot can't be put on trial"),
+            Error::SlotStateFailed => f.write_str("accessing the slot state in flash failed"),
+            Error::DecompressFailed => f.write_str("compressed data is corrupted"),
+            Error::FrameTooLarge { max } => write!(f, "the request doesn't fit stage0's {max} byte frame buffer"),
+            Error::Malformed => f.write_str("stage0 couldn't decode the request"),
+            Error::UnknownRequest { variant } => {
+                write!(f, "stage0 doesn't know request #{variant}, it's older than this soup-cli")
+            }
+            Error::NotThumb { addr } => write!(f, "0x{addr:08X} is missing the Thumb bit"),
+        }
+    }
+}
+
+impl core::fmt::Display for InvalidImageReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InvalidImageReason::Misaligned { addr, align } => {
+                write!(f, "vector table at 0x{addr:08X} isn't aligned to {align} bytes")
+            }
+            InvalidImageReason::NotInScratchOrFlash { addr } => {
+                write!(f, "0x{addr:08X} is neither in scratch RAM, nor in application flash")
+            }
+            InvalidImageReason::StackPointerNotInRam { sp } => {
+                write!(f, "initial stack pointer 0x{sp:08X} isn't a word aligned RAM address")
+            }
+            InvalidImageReason::ResetVectorNotThumb { reset } => {
+                write!(f, "reset vector 0x{reset:08X} is missing the Thumb bit")
+            }
+            InvalidImageReason::ResetVectorOutsideImage { reset } => {
+                write!(f, "reset vector 0x{reset:08X} points outside of the image")
+            }
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -70,6 +402,40 @@ pub struct Poked {
     pub addr: usize,
 }
 
+/// CRC-32/ISO-HDLC (the zlib/PNG one) over `[addr, addr + len)`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Crc32 {
+    pub addr: usize,
+    pub len: usize,
+    pub crc: u32,
+}
+
+/// SHA-256 over `[addr, addr + len)`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Sha256 {
+    pub addr: usize,
+    pub len: usize,
+    pub digest: [u8; 32],
+}
+
+/// Where the slots are, and which one boots next
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Slots {
+    pub layout: SlotLayout,
+    pub state: SlotState,
+}
+
+/// The answer to a `Sync`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Synced {
+    /// How many `PokeBytesQuiet` succeeded since the previous `Sync`
+    pub pokes: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub struct UnalignedFlashAddr{
@@ -77,6 +443,94 @@ pub struct UnalignedFlashAddr{
     pub align: usize,
 }
 
+/// A semver-ish protocol version.
+///
+/// Peers with the same major version share a wire format. A newer minor
+/// version only ever adds things at the end of an enum, so a host must
+/// check the minor version of the device before using anything newer.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    /// Build a version from the `CARGO_PKG_VERSION_*` strings at compile time.
+    ///
+    /// Panics (at compile time, when used in a const) on anything that isn't
+    /// a plain decimal number.
+    pub const fn from_pkg(major: &str, minor: &str, patch: &str) -> Self {
+        const fn parse(s: &str) -> u16 {
+            let val = parse_decimal(s);
+            assert!(val <= u16::MAX as u64, "version numbers go up to 65535");
+            val as u16
+        }
+
+        Self {
+            major: parse(major),
+            minor: parse(minor),
+            patch: parse(patch),
+        }
+    }
+
+    /// Can we talk to a peer with version `other` at all?
+    pub fn is_compatible(&self, other: &Version) -> bool {
+        self.major == other.major
+    }
+}
+
+impl core::fmt::Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct MemRange {
+    pub start: usize,
+    pub len: usize,
+}
+
+impl MemRange {
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// Does `[addr, addr + len)` fit completely inside this range?
+    pub fn contains(&self, addr: usize, len: usize) -> bool {
+        match addr.checked_add(len) {
+            Some(end) => (addr >= self.start) && (end <= self.end()),
+            None => false,
+        }
+    }
+}
+
+/// Everything the host needs to know about the memory layout of the device
+/// it is talking to, so it doesn't have to hardcode it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    /// Version of the stage0 firmware
+    pub bootloader_version: Version,
+    /// Version of the `stage0-icd` crate stage0 was built with
+    pub icd_version: Version,
+    /// The 64-bit FICR device ID
+    pub device_id: u64,
+    /// RAM region usable with `PeekBytes`/`PokeBytes`, and as a `FlashCopy` source
+    pub scratch: MemRange,
+    /// RAM region used to pass boot commands across a reset
+    pub magic: MemRange,
+    /// Total size of the internal flash, starting at address zero
+    pub flash_size: usize,
+    /// Erase size of the internal flash. `FlashCopy` destinations must be aligned to this
+    pub flash_page_size: usize,
+    /// Flash region holding stage0 itself, which `FlashCopy` refuses to touch
+    pub bootloader: MemRange,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "use-defmt", derive(defmt::Format))]
 pub enum Response<'a> {
@@ -87,6 +541,24 @@ pub enum Response<'a> {
     #[serde(borrow)]
     PeekBytesFlash(PeekBytes<'a>),
     FlashCopied,
+    Info(DeviceInfo),
+    Crc32(Crc32),
+    Crc32Flash(Crc32),
+    Sha256(Sha256),
+    Sha256Flash(Sha256),
+    Bootloading {
+        addr: u32,
+    },
+    Synced(Synced),
+    Rebooting(RebootTarget),
+    Slots(Slots),
+    TrialBooting {
+        slot: Slot,
+    },
+    /// What a `Call` returned
+    Called {
+        r0: u32,
+    },
 }
 
 #[cfg(feature = "use-std")]
@@ -105,6 +577,273 @@ impl<'a> Response<'a> {
             Response::Poked(Poked { addr }) => Response::Poked(Poked { addr: *addr }),
             Response::MagicCleared => Response::MagicCleared,
             Response::FlashCopied => Response::FlashCopied,
+            Response::Info(info) => Response::Info(info.clone()),
+            Response::Crc32(crc) => Response::Crc32(*crc),
+            Response::Crc32Flash(crc) => Response::Crc32Flash(*crc),
+            Response::Sha256(sha) => Response::Sha256(*sha),
+            Response::Sha256Flash(sha) => Response::Sha256Flash(*sha),
+            Response::Bootloading { addr } => Response::Bootloading { addr: *addr },
+            Response::Synced(synced) => Response::Synced(*synced),
+            Response::Rebooting(target) => Response::Rebooting(*target),
+            Response::Slots(slots) => Response::Slots(*slots),
+            Response::TrialBooting { slot } => Response::TrialBooting { slot: *slot },
+            Response::Called { r0 } => Response::Called { r0: *r0 },
+        }
+    }
+}
+
+/// Describe every type in [`ToStage0`] and [`FromStage0`], for tooling that
+/// can't use this crate: each enum's variants by their tag on the wire, and
+/// each struct's fields in the order they're sent, with their types.
+///
+/// Traced from the serde impls, so it can't disagree with what's actually
+/// sent.
+#[cfg(any(test, feature = "schema"))]
+pub fn schema() -> serde_reflection::Result<serde_reflection::Registry> {
+    let mut tracer = serde_reflection::Tracer::new(Default::default());
+    tracer.trace_simple_type::<ToStage0<'_>>()?;
+    tracer.trace_simple_type::<FromStage0<'_>>()?;
+    // Enums inside them only get all their variants traced on their own, and
+    // tracing fails until a new one is added here
+    tracer.trace_simple_type::<Request<'_>>()?;
+    tracer.trace_simple_type::<Result<Response<'_>, Error>>()?;
+    tracer.trace_simple_type::<Response<'_>>()?;
+    tracer.trace_simple_type::<Error>()?;
+    tracer.trace_simple_type::<InvalidImageReason>()?;
+    tracer.trace_simple_type::<HeaderError>()?;
+    tracer.trace_simple_type::<RebootTarget>()?;
+    tracer.trace_simple_type::<Slot>()?;
+    tracer.registry()
+}
+
+#[cfg(test)]
+mod test {
+    //! Golden bytes for every message. If one of these changes, the wire
+    //! format changed, and the version in `Cargo.toml` needs a bump.
+
+    use super::*;
+    use serde::Serialize;
+
+    fn golden<T: Serialize>(val: &T, bytes: &[u8]) {
+        assert_eq!(postcard::to_stdvec(val).unwrap(), bytes, "{}", core::any::type_name::<T>());
+    }
+
+    // Every request and response below is sent with this header
+    const HDR: Header = Header { seq: 0x1234 };
+
+    fn req(r: Request<'_>, bytes: &[u8]) {
+        golden(&ToStage0::Request(HDR, r), &[&[0x01, 0xB4, 0x24], bytes].concat());
+    }
+
+    fn resp(r: Result<Response<'_>, Error>, bytes: &[u8]) {
+        golden(&FromStage0::Response(HDR, r), &[&[0x01, 0xB4, 0x24], bytes].concat());
+    }
+
+    const VER: Version = Version { major: 3, minor: 1, patch: 2 };
+    const HELLO: Hello = Hello { protocol: PROTOCOL, version: VER };
+    const HELLO_BYTES: &[u8] = &[0xD3, 0xE0, 0xB0, 0xA2, 0x04, 0x03, 0x01, 0x02];
+
+    #[test]
+    fn version_matches_cargo() {
+        assert_eq!(ICD_VERSION.to_string(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(Hello::CURRENT.version, ICD_VERSION);
+    }
+
+    #[test]
+    fn hello() {
+        golden(&HELLO, HELLO_BYTES);
+        golden(&ToStage0::Hello(HELLO), &[&[0x00], HELLO_BYTES].concat());
+        golden(&FromStage0::Hello(HELLO), &[&[0x00], HELLO_BYTES].concat());
+    }
+
+    #[test]
+    fn requests() {
+        req(Request::PeekBytes { addr: 0x2000_0000, len: 256 }, &[0x00, 0x80, 0x80, 0x80, 0x80, 0x02, 0x80, 0x02]);
+        req(Request::PokeBytes { addr: 0x10, val: Managed::from_borrowed(&[1, 2, 3]) }, &[0x01, 0x10, 0x03, 0x01, 0x02, 0x03]);
+        req(Request::ClearMagic, &[0x02]);
+        req(Request::Reboot { target: RebootTarget::Stage0 }, &[0x03, 0x00]);
+        req(Request::Reboot { target: RebootTarget::Ram }, &[0x03, 0x01]);
+        req(Request::Reboot { target: RebootTarget::Flash { addr: 0x8000 } }, &[0x03, 0x02, 0x80, 0x80, 0x02]);
+        req(Request::Bootload { addr: 0x2000_0000 }, &[0x04, 0x80, 0x80, 0x80, 0x80, 0x02]);
+        req(Request::PeekBytesFlash { addr: 0x8000, len: 16 }, &[0x05, 0x80, 0x80, 0x02, 0x10]);
+        req(Request::FlashCopy { ram_start: 0x2000_0000, flash_start: 0x8000, len: 4096 }, &[0x06, 0x80, 0x80, 0x80, 0x80, 0x02, 0x80, 0x80, 0x02, 0x80, 0x20]);
+        req(Request::GetInfo, &[0x07]);
+        req(Request::Crc32 { addr: 0x10, len: 0x20 }, &[0x08, 0x10, 0x20]);
+        req(Request::Crc32Flash { addr: 0x10, len: 0x20 }, &[0x09, 0x10, 0x20]);
+        req(Request::Sha256 { addr: 0x10, len: 0x20 }, &[0x0A, 0x10, 0x20]);
+        req(Request::Sha256Flash { addr: 0x10, len: 0x20 }, &[0x0B, 0x10, 0x20]);
+        req(Request::PokeBytesQuiet { addr: 0x10, val: Managed::from_borrowed(&[1, 2, 3]) }, &[0x0C, 0x10, 0x03, 0x01, 0x02, 0x03]);
+        req(Request::Sync, &[0x0D]);
+
+        let header = ImageHeader::new(0x8000, 0x8000, soup_image::AppVersion { major: 1, minor: 2, patch: 3 }, &[0xAA; 4]);
+        let header_bytes = [
+            0xD3, 0x92, 0xB5, 0xBA, 0x04, // magic
+            0x01, // header_version
+            0x04, // image_len
+            0x80, 0x80, 0x02, // load_addr
+            0x80, 0x80, 0x02, // entry
+            0xDE, 0xC0, 0xDB, 0xAC, 0x0B, // image_crc
+            0x01, 0x02, 0x03, // app_version
+            0xD5, 0xF9, 0x86, 0xA5, 0x0B, // header_crc
+        ];
+        req(Request::BootloadImage { header }, &[&[0x0E][..], &header_bytes].concat());
+        req(Request::FlashCopyImage { ram_start: 0x10, header }, &[&[0x0F, 0x10][..], &header_bytes].concat());
+
+        let signature = Signature { r: [0x11; 32], s: [0x22; 32] };
+        let sig_bytes = [[0x11; 32], [0x22; 32]].concat();
+        req(Request::BootloadSignedImage { header, signature }, &[&[0x10][..], &header_bytes, &sig_bytes].concat());
+        req(Request::FlashCopySignedImage { ram_start: 0x10, header, signature }, &[&[0x11, 0x10][..], &header_bytes, &sig_bytes].concat());
+        req(Request::GetSlots, &[0x12]);
+        req(Request::TrialBoot { slot: Slot::B }, &[0x13, 0x01]);
+        req(Request::PokeBytesQuietLz4 { addr: 0x10, len: 0x20, val: Managed::from_borrowed(&[1, 2, 3]) }, &[0x14, 0x10, 0x20, 0x03, 0x01, 0x02, 0x03]);
+        req(Request::Call { addr: 0x2000_0001, args: [1, 2, 3, 0x80] }, &[0x15, 0x81, 0x80, 0x80, 0x80, 0x02, 0x01, 0x02, 0x03, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn responses() {
+        resp(Ok(Response::PeekBytes(PeekBytes { addr: 0x10, val: Managed::from_borrowed(&[0xAA]) })), &[0x00, 0x00, 0x10, 0x01, 0xAA]);
+        resp(Ok(Response::Poked(Poked { addr: 0x10 })), &[0x00, 0x01, 0x10]);
+        resp(Ok(Response::MagicCleared), &[0x00, 0x02]);
+        resp(Ok(Response::PeekBytesFlash(PeekBytes { addr: 0x10, val: Managed::from_borrowed(&[0xAA]) })), &[0x00, 0x03, 0x10, 0x01, 0xAA]);
+        resp(Ok(Response::FlashCopied), &[0x00, 0x04]);
+        resp(
+            Ok(Response::Info(DeviceInfo {
+                bootloader_version: VER,
+                icd_version: VER,
+                device_id: 0x0102_0304_0506_0708,
+                scratch: MemRange { start: 0x2000_0000, len: 0x3_8000 },
+                magic: MemRange { start: 0x2003_FFC0, len: 64 },
+                flash_size: 0x10_0000,
+                flash_page_size: 4096,
+                bootloader: MemRange { start: 0, len: 0x8000 },
+            })),
+            &[
+                0x00, 0x05,
+                0x03, 0x01, 0x02,
+                0x03, 0x01, 0x02,
+                0x88, 0x8E, 0x98, 0xA8, 0xC0, 0xE0, 0x80, 0x81, 0x01,
+                0x80, 0x80, 0x80, 0x80, 0x02, 0x80, 0x80, 0x0E,
+                0xC0, 0xFF, 0x8F, 0x80, 0x02, 0x40,
+                0x80, 0x80, 0x40,
+                0x80, 0x20,
+                0x00, 0x80, 0x80, 0x02,
+            ],
+        );
+
+        let crc = Crc32 { addr: 0x10, len: 0x20, crc: 0xCBF4_3926 };
+        resp(Ok(Response::Crc32(crc)), &[0x00, 0x06, 0x10, 0x20, 0xA6, 0xF2, 0xD0, 0xDF, 0x0C]);
+        resp(Ok(Response::Crc32Flash(crc)), &[0x00, 0x07, 0x10, 0x20, 0xA6, 0xF2, 0xD0, 0xDF, 0x0C]);
+
+        let sha = Sha256 { addr: 0x10, len: 0x20, digest: [0xAB; 32] };
+        resp(Ok(Response::Sha256(sha)), &[&[0x00, 0x08, 0x10, 0x20][..], &[0xAB; 32]].concat());
+        resp(Ok(Response::Sha256Flash(sha)), &[&[0x00, 0x09, 0x10, 0x20][..], &[0xAB; 32]].concat());
+
+        resp(Ok(Response::Bootloading { addr: 0x2000_0000 }), &[0x00, 0x0A, 0x80, 0x80, 0x80, 0x80, 0x02]);
+        resp(Ok(Response::Synced(Synced { pokes: 300 })), &[0x00, 0x0B, 0xAC, 0x02]);
+        resp(Ok(Response::Rebooting(RebootTarget::Flash { addr: 0x8000 })), &[0x00, 0x0C, 0x02, 0x80, 0x80, 0x02]);
+        resp(
+            Ok(Response::Slots(Slots {
+                layout: soup_slots::NRF52840,
+                state: SlotState { active: Slot::A, trial: Some(Trial { slot: Slot::B, booted: true }) },
+            })),
+            &[
+                0x00, 0x0D,
+                0x80, 0x80, 0x02, // slot_a
+                0x80, 0xE0, 0x20, // slot_b
+                0x80, 0xE0, 0x1E, // slot_size
+                0x80, 0xC0, 0x3F, // meta
+                0x80, 0x40, // meta_size
+                0x00, 0x01, 0x01, 0x01, // state
+            ],
+        );
+        resp(Ok(Response::TrialBooting { slot: Slot::B }), &[0x00, 0x0E, 0x01]);
+        resp(Ok(Response::Called { r0: 0xFFFF_FFFF }), &[0x00, 0x0F, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+    }
+
+    #[test]
+    fn errors() {
+        resp(Err(Error::AddressOutOfRange { request: 1, len: 2, min: 3, max: 4 }), &[0x01, 0x00, 0x01, 0x02, 0x03, 0x04]);
+        resp(Err(Error::RangeTooLarge { request: 300, max: 256 }), &[0x01, 0x01, 0xAC, 0x02, 0x80, 0x02]);
+        resp(Err(Error::UnalignedFlashAddr(UnalignedFlashAddr { addr: 1, align: 4096 })), &[0x01, 0x02, 0x01, 0x80, 0x20]);
+        resp(Err(Error::CantOverwriteBootloader), &[0x01, 0x03]);
+        resp(Err(Error::FlashCopyFailed), &[0x01, 0x04]);
+        resp(Err(Error::NoRamImage), &[0x01, 0x05]);
+        resp(Err(Error::InvalidImage { reason: InvalidImageReason::Misaligned { addr: 0x10, align: 256 } }), &[0x01, 0x06, 0x00, 0x10, 0x80, 0x02]);
+        resp(Err(Error::InvalidImage { reason: InvalidImageReason::NotInScratchOrFlash { addr: 0x10 } }), &[0x01, 0x06, 0x01, 0x10]);
+        resp(Err(Error::InvalidImage { reason: InvalidImageReason::StackPointerNotInRam { sp: 0x10 } }), &[0x01, 0x06, 0x02, 0x10]);
+        resp(Err(Error::InvalidImage { reason: InvalidImageReason::ResetVectorNotThumb { reset: 0x10 } }), &[0x01, 0x06, 0x03, 0x10]);
+        resp(Err(Error::InvalidImage { reason: InvalidImageReason::ResetVectorOutsideImage { reset: 0x10 } }), &[0x01, 0x06, 0x04, 0x10]);
+        resp(Err(Error::BadImage(HeaderError::ImageCrcMismatch)), &[0x01, 0x07, 0x05]);
+        resp(Err(Error::BadImage(HeaderError::BadSignature)), &[0x01, 0x07, 0x07]);
+        resp(Err(Error::SignatureRequired), &[0x01, 0x08]);
+        resp(Err(Error::SlotIsActive), &[0x01, 0x09]);
+        resp(Err(Error::SlotStateFailed), &[0x01, 0x0A]);
+        resp(Err(Error::DecompressFailed), &[0x01, 0x0B]);
+        resp(Err(Error::FrameTooLarge { max: 512 }), &[0x01, 0x0C, 0x80, 0x04]);
+        resp(Err(Error::Malformed), &[0x01, 0x0D]);
+        resp(Err(Error::UnknownRequest { variant: 30 }), &[0x01, 0x0E, 0x1E]);
+        resp(Err(Error::NotThumb { addr: 0x10 }), &[0x01, 0x0F, 0x10]);
+        golden(&FromStage0::FrameError(Error::Malformed), &[0x02, 0x0D]);
+    }
+
+    fn cobs(msg: &ToStage0<'_>) -> Vec<u8> {
+        let mut raw = postcard::to_stdvec_cobs(msg).unwrap();
+        assert_eq!(raw.pop(), Some(0x00));
+        raw
+    }
+
+    #[test]
+    fn request_variants() {
+        let last = Request::Call { addr: 0, args: [0; 4] };
+        let bytes = postcard::to_stdvec(&last).unwrap();
+        assert_eq!(u32::from(bytes[0]) + 1, Request::VARIANTS);
+    }
+
+    #[test]
+    fn peek() {
+        let hdr = Header { seq: 0xFFFF };
+        let raw = cobs(&ToStage0::Request(hdr, Request::PokeBytes { addr: 0, val: Managed::from_borrowed(&[0; 300]) }));
+        assert_eq!(peek_request(&raw), Some((hdr, 1)));
+
+        // Cut off, like a frame that overflowed stage0's buffer
+        assert_eq!(peek_request(&raw[..6]), Some((hdr, 1)));
+        assert_eq!(peek_request(&raw[..3]), None);
+
+        // Broken after the variant
+        let mut raw = cobs(&ToStage0::Request(HDR, Request::Sync));
+        raw.extend_from_slice(&[0x05, 0xFF]);
+        assert_eq!(peek_request(&raw), Some((HDR, 0x0D)));
+
+        // From a newer host
+        let raw = [0x06, 0x01, 0xB4, 0x24, 0x80, 0x02];
+        assert_eq!(peek_request(&raw), Some((HDR, 0x100)));
+
+        assert_eq!(peek_request(&cobs(&ToStage0::Hello(HELLO))), None);
+        assert_eq!(peek_request(&[]), None);
+    }
+
+    #[test]
+    fn schema() {
+        use serde_reflection::{ContainerFormat, Format, VariantFormat};
+
+        let registry = super::schema().unwrap();
+        let Some(ContainerFormat::Enum(requests)) = registry.get("Request") else {
+            panic!("no Request in {registry:?}");
+        };
+        assert_eq!(requests.len() as u32, Request::VARIANTS);
+        assert_eq!(requests[&0].name, "PeekBytes");
+        assert_eq!(requests[&21].name, "Call");
+
+        // Through the `Managed` in `PokeBytes`, down to the bytes
+        let VariantFormat::Struct(fields) = &requests[&1].value else {
+            panic!("{:?}", requests[&1]);
+        };
+        assert_eq!(fields[1].name, "val");
+        assert_eq!(fields[1].value, Format::Bytes);
+
+        // And the types from the other crates
+        for name in ["ImageHeader", "Signature", "Slot", "Layout", "Trial"] {
+            assert!(registry.contains_key(name), "no {name}");
         }
     }
 }